@@ -0,0 +1,88 @@
+//! Heap use of `DynamicCacheLocal`, with and without a sketch, against `FingerprintCacheLocal`,
+//! for a long request memory with path keys.
+//!
+//! Run with `cargo bench --bench memory`.
+
+use dynamic_lru_cache::{DynamicCacheLocal, FingerprintCacheLocal};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+const MEM_LEN: usize = 100_000;
+const KEY_SPACE: u64 = 200_000;
+const REQUESTS: usize = 400_000;
+
+/// The system allocator, keeping track of how many bytes are currently allocated.
+struct Counting;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+/// The keys to request, in order. Half of the requests go to a small set of hot keys.
+fn requests() -> impl Iterator<Item = PathBuf> {
+    let mut state = 0x2545_F491_4F6C_DD1Du64;
+    (0..REQUESTS).map(move |_| {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        let id = if state & 1 == 0 {
+            state % 1000
+        } else {
+            state % KEY_SPACE
+        };
+        PathBuf::from(format!("/srv/assets/shared/dictionaries/dict-{id:08}.bin"))
+    })
+}
+
+/// Run `f` and report how much more heap memory is in use afterwards, keeping its result alive.
+fn measure<T>(name: &str, f: impl FnOnce() -> T) -> T {
+    let before = ALLOCATED.load(Ordering::Relaxed);
+    let ret = f();
+    let used = ALLOCATED.load(Ordering::Relaxed) - before;
+    println!("{name:>24}: {:>8.2} MiB", used as f64 / (1 << 20) as f64);
+    ret
+}
+
+fn main() {
+    let regular = measure("DynamicCacheLocal", || {
+        let mut cache = DynamicCacheLocal::new(MEM_LEN);
+        for path in requests() {
+            cache.get_or_insert(&path, || 0u64);
+        }
+        cache
+    });
+    let sketch = measure("DynamicCacheLocal sketch", || {
+        let mut cache = DynamicCacheLocal::with_sketch(MEM_LEN);
+        for path in requests() {
+            cache.get_or_insert(&path, || 0u64);
+        }
+        cache
+    });
+    let fingerprint = measure("FingerprintCacheLocal", || {
+        let mut cache = FingerprintCacheLocal::new(MEM_LEN);
+        for path in requests() {
+            cache.get_or_insert(path, || 0u64);
+        }
+        cache
+    });
+    println!(
+        "The caches hold {}, {} and {} values",
+        regular.size(),
+        sketch.size(),
+        fingerprint.size()
+    );
+}
@@ -0,0 +1,94 @@
+//! Multi-threaded throughput of `DynamicCache` against `ShardedDynamicCache` and
+//! `BufferedDynamicCache`.
+//!
+//! Run with `cargo bench --bench throughput`. Each thread makes requests through
+//! `get_or_insert` for keys drawn from a skewed distribution, so most requests are for a small
+//! set of hot keys that end up stored in the cache.
+
+use dynamic_lru_cache::{BufferedDynamicCache, DynamicCache, ShardedDynamicCache};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const MEM_LEN: usize = 4096;
+const KEY_SPACE: u64 = 16384;
+const REQUESTS_PER_THREAD: usize = 200_000;
+const THREAD_COUNTS: [usize; 4] = [1, 4, 8, 32];
+
+/// A small xorshift generator, so the benchmark measures the cache rather than the RNG.
+struct XorShift(u64);
+
+impl XorShift {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// Pick a key, with about 90% of picks landing on the first 1/64th of the key space.
+    fn key(&mut self) -> u64 {
+        let r = self.next();
+        match r % 10 {
+            0 => r % KEY_SPACE,
+            _ => r % (KEY_SPACE / 64),
+        }
+    }
+}
+
+/// Run `threads` threads making requests through `get`, and return the overall request rate.
+fn run<C, F>(cache: C, threads: usize, get: F) -> f64
+where
+    C: Clone + Send + 'static,
+    F: Fn(&C, u64) + Send + Sync + 'static,
+{
+    let get = Arc::new(get);
+    let start = Instant::now();
+    let handles: Vec<_> = (0..threads)
+        .map(|t| {
+            let (cache, get) = (cache.clone(), get.clone());
+            thread::spawn(move || {
+                let mut rng = XorShift(0x9E37_79B9_7F4A_7C15 ^ (t as u64 + 1));
+                for _ in 0..REQUESTS_PER_THREAD {
+                    get(&cache, rng.key());
+                }
+            })
+        })
+        .collect();
+    handles.into_iter().for_each(|h| h.join().unwrap());
+    rate(threads * REQUESTS_PER_THREAD, start.elapsed())
+}
+
+fn rate(requests: usize, elapsed: Duration) -> f64 {
+    requests as f64 / elapsed.as_secs_f64()
+}
+
+fn main() {
+    let shard_count = thread::available_parallelism().map_or(8, |n| n.get() * 4);
+    println!(
+        "{:>8} {:>16} {:>16} {:>16}",
+        "threads", "DynamicCache", "Sharded", "Buffered"
+    );
+    for threads in THREAD_COUNTS {
+        let single = run(DynamicCache::new(MEM_LEN), threads, |c, k| {
+            c.get_or_insert(&k, || k);
+        });
+        let sharded = run(
+            ShardedDynamicCache::new(MEM_LEN, shard_count),
+            threads,
+            |c, k| {
+                c.get_or_insert(&k, || k);
+            },
+        );
+        let buffered = run(BufferedDynamicCache::new(MEM_LEN), threads, |c, k| {
+            c.get_or_insert(&k, || k);
+        });
+        println!(
+            "{:>8} {:>12.2} M/s {:>12.2} M/s {:>12.2} M/s",
+            threads,
+            single / 1e6,
+            sharded / 1e6,
+            buffered / 1e6
+        );
+    }
+}
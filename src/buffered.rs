@@ -0,0 +1,362 @@
+//! A shared cache whose hits don't need to take its lock.
+
+use crate::fingerprint::FingerprintHasher;
+use crate::{CacheStats, DynamicCacheLocal, EvictionReason};
+use std::borrow::Borrow;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, BuildHasherDefault, Hash};
+use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+
+/// The number of buffered requests a thread collects before applying them to the cache.
+const BATCH_LEN: usize = 64;
+
+/// Get the request buffer index for the current thread. Each thread gets its own index, so
+/// threads only share a buffer once there are more threads than buffers.
+fn buffer_index() -> usize {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    thread_local! {
+        static INDEX: usize = NEXT.fetch_add(1, Ordering::Relaxed);
+    }
+    INDEX.with(|i| *i)
+}
+
+/// A shared cache like [`DynamicCache`](crate::DynamicCache), tuned for workloads where most
+/// requests are hits.
+///
+/// Stored values are mirrored in a map that many threads can read at once, and hits are served
+/// from it without locking the cache. The request each hit represents is put in a per-thread
+/// buffer instead, and the buffered requests are applied to the cache in batches: whenever a
+/// buffer fills up, and before anything else that locks the cache, including every miss. This
+/// means the cache's memory sees requests a little out of order, and metrics only include hits
+/// once they've been applied. Buffered requests only hold the key's hash, so serving a hit
+/// doesn't allocate. A hit whose value leaves the cache before its request is applied still
+/// counts as a hit, but isn't remembered.
+///
+/// Admission is unaffected: a key only has a value stored after enough requests for it, and all
+/// requests for keys without a stored value go straight to the cache. Buffered requests only
+/// delay when stored values are considered recently used.
+///
+/// Time-based expiry is not supported by this cache, as the read path has no clock to check.
+#[derive(Debug)]
+pub struct BufferedDynamicCache<K, V, S = RandomState> {
+    inner: Arc<Shared<K, V, S>>,
+}
+
+#[derive(Debug)]
+struct Shared<K, V, S> {
+    cache: Mutex<DynamicCacheLocal<K, V, S>>,
+    /// A copy of every value stored in the cache. Only changed while `cache` is locked.
+    stored: RwLock<Mirror<K, V, S>>,
+    /// Hashes of the keys requested by hits served from `stored`, which haven't been applied to
+    /// the cache yet. A hash is applied to whichever stored key `stored` currently holds for it,
+    /// so when two stored keys' hashes collide, hits on one are counted as requests for the
+    /// other, or dropped if neither is held any more. Hashes only collide once in 2^64 pairs of
+    /// keys, so this is left alone rather than storing the full key for every hit.
+    buffers: Box<[Mutex<Vec<u64>>]>,
+}
+
+/// The stored values of a [`BufferedDynamicCache`], readable without locking the cache.
+#[derive(Debug)]
+struct Mirror<K, V, S> {
+    values: HashMap<K, Arc<V>, S>,
+    /// The key of each stored value by its hash, to look up the keys of buffered requests. Only
+    /// one key is held for each hash; see `Shared::buffers`.
+    keys: HashMap<u64, K, BuildHasherDefault<FingerprintHasher>>,
+}
+
+impl<K: Clone + Eq + Hash, V, S: BuildHasher> Mirror<K, V, S> {
+    fn insert(&mut self, key: K, value: Arc<V>) {
+        self.keys
+            .insert(self.values.hasher().hash_one(&key), key.clone());
+        self.values.insert(key, value);
+    }
+
+    fn remove(&mut self, key: &K) {
+        self.values.remove(key);
+        let hash = self.values.hasher().hash_one(key);
+        // Keys with colliding hashes only keep the latest one
+        if self.keys.get(&hash) == Some(key) {
+            self.keys.remove(&hash);
+        }
+    }
+}
+
+impl<K, V, S> Clone for BufferedDynamicCache<K, V, S> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<K: Clone + Eq + Hash, V> BufferedDynamicCache<K, V> {
+    /// Create and initialize a new cache.
+    pub fn new(mem_len: usize) -> Self {
+        Self::with_hasher(mem_len, RandomState::new())
+    }
+}
+
+impl<K: Clone + Eq + Hash, V, S: BuildHasher + Clone> BufferedDynamicCache<K, V, S> {
+    /// Create and initialize a new cache, using the given hash builder to hash keys. The same
+    /// warnings given for [`HashMap::with_hasher`] apply here.
+    pub fn with_hasher(mem_len: usize, hash_builder: S) -> Self {
+        let mut cache = DynamicCacheLocal::with_hasher(mem_len, hash_builder.clone());
+        cache.evictions.collect();
+        let buffer_count = std::thread::available_parallelism().map_or(8, |n| n.get() * 2);
+        Self {
+            inner: Arc::new(Shared {
+                cache: Mutex::new(cache),
+                stored: RwLock::new(Mirror {
+                    values: HashMap::with_hasher(hash_builder),
+                    keys: HashMap::default(),
+                }),
+                buffers: (0..buffer_count).map(|_| Mutex::default()).collect(),
+            }),
+        }
+    }
+
+    /// Run `f` on the locked cache after applying all buffered requests, keeping the mirror of
+    /// stored values up to date. `f` returns its result and possibly a key whose stored value
+    /// should be mirrored afterwards. Any values removed are reported to the eviction listener
+    /// once the lock has been released.
+    fn with_lock<R, F>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut DynamicCacheLocal<K, V, S>) -> (R, Option<K>),
+    {
+        let mut cache = self.inner.cache.lock().unwrap();
+        {
+            let stored = self.inner.stored.read().unwrap();
+            for buffer in self.inner.buffers.iter() {
+                let requests = std::mem::take(&mut *buffer.lock().unwrap());
+                for hash in requests {
+                    match stored.keys.get(&hash) {
+                        Some(key) => cache.replay_hit(key.clone()),
+                        // The value left before the request could be applied, so there's no
+                        // key to remember it for
+                        None => cache.hits += 1,
+                    }
+                }
+            }
+        }
+        let (ret, admitted) = f(&mut cache);
+        let evicted = cache.evictions.take_deferred();
+        if evicted.is_some() || admitted.is_some() {
+            let mut stored = self.inner.stored.write().unwrap();
+            for key in evicted.iter().flat_map(|e| e.keys()) {
+                stored.remove(key);
+            }
+            if let Some((k, v)) = admitted.and_then(|k| cache.stored(&k)) {
+                stored.insert(k.clone(), v.clone());
+            }
+        }
+        drop(cache);
+        if let Some(evicted) = evicted {
+            evicted.report();
+        }
+        ret
+    }
+
+    /// Buffer a request for a key that was served without locking the cache, by its hash.
+    fn record(&self, hash: u64) {
+        let buffers = &self.inner.buffers;
+        let mut buffer = buffers[buffer_index() % buffers.len()].lock().unwrap();
+        buffer.push(hash);
+        if buffer.len() >= BATCH_LEN {
+            drop(buffer);
+            self.flush();
+        }
+    }
+
+    /// Apply all buffered requests to the cache's memory.
+    pub fn flush(&self) {
+        self.with_lock(|_| ((), None))
+    }
+
+    /// Attempt to retrieve a value from the cache. This updates the cache's memory of what values
+    /// have been requested, though a hit may only be applied to it later.
+    ///
+    /// The key may be any borrowed form of the cache's key type, but [`Hash`] and [`Eq`] on the
+    /// borrowed form *must* match those for the key type.
+    pub fn get<Q>(&self, key: &Q) -> Option<Arc<V>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq + ToOwned<Owned = K>,
+    {
+        let stored = self.inner.stored.read().unwrap();
+        if let Some(v) = stored.values.get(key).cloned() {
+            let hash = stored.values.hasher().hash_one(key);
+            drop(stored);
+            self.record(hash);
+            return Some(v);
+        }
+        drop(stored);
+        self.with_lock(|cache| (cache.get(key), None))
+    }
+
+    /// Attempt to remove a value from the cache. The cache's memory of requests for the key is
+    /// kept.
+    ///
+    /// The key may be any borrowed form of the cache's key type, but [`Hash`] and [`Eq`] on the
+    /// borrowed form *must* match those for the key type.
+    pub fn pop<Q>(&self, key: &Q) -> Option<Arc<V>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.with_lock(|cache| (cache.pop(key), None))
+    }
+
+    /// Insert a value into the cache, following the same rules as
+    /// [`DynamicCacheLocal::insert`].
+    ///
+    /// The key may be any borrowed form of the cache's key type, but [`Hash`] and [`Eq`] on the
+    /// borrowed form *must* match those for the key type.
+    pub fn insert<Q>(&self, key: &Q, value: V) -> Arc<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.with_lock(|cache| {
+            let v = cache.insert(key, value);
+            let admitted = cache.stored(key).map(|(k, _)| k.clone());
+            (v, admitted)
+        })
+    }
+
+    /// Fetch an item via the cache, potentially filling in the cache on a miss via the function
+    /// `f`. The cache is unlocked while calling the function, so `f` may be called more than once
+    /// with the same parameters if there are several threads using the cache.
+    ///
+    /// The key may be any borrowed form of the cache's key type, but [`Hash`] and [`Eq`] on the
+    /// borrowed form *must* match those for the key type.
+    pub fn get_or_insert<Q, F>(&self, key: &Q, f: F) -> Arc<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq + ToOwned<Owned = K>,
+        F: FnOnce() -> V,
+    {
+        self.get(key).unwrap_or_else(|| self.insert(key, f()))
+    }
+
+    /// Retrieve a value from the cache without updating the cache's memory of what values have
+    /// been requested, or its hit/miss metrics. This never locks the cache.
+    ///
+    /// The key may be any borrowed form of the cache's key type, but [`Hash`] and [`Eq`] on the
+    /// borrowed form *must* match those for the key type.
+    pub fn peek<Q>(&self, key: &Q) -> Option<Arc<V>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.inner.stored.read().unwrap().values.get(key).cloned()
+    }
+
+    /// Check if a value is currently stored in the cache. This never locks the cache.
+    ///
+    /// The key may be any borrowed form of the cache's key type, but [`Hash`] and [`Eq`] on the
+    /// borrowed form *must* match those for the key type.
+    pub fn contains<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.inner.stored.read().unwrap().values.contains_key(key)
+    }
+
+    /// Get the number of requests for a key in the cache's recent request memory, after applying
+    /// all buffered requests.
+    ///
+    /// The key may be any borrowed form of the cache's key type, but [`Hash`] and [`Eq`] on the
+    /// borrowed form *must* match those for the key type.
+    pub fn recent_request_count<Q>(&self, key: &Q) -> u32
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.with_lock(|cache| (cache.recent_request_count(key), None))
+    }
+
+    /// Get the number of items currently stored in the cache.
+    pub fn size(&self) -> usize {
+        self.inner.stored.read().unwrap().values.len()
+    }
+
+    /// Change the maximum total weight of stored items. If the cache is currently heavier than
+    /// this, the least recently requested items are removed immediately until it fits.
+    pub fn set_max_weight(&self, max_weight: usize) {
+        self.with_lock(|cache| (cache.set_max_weight(max_weight), None))
+    }
+
+    /// Set the function used to weigh stored items, replacing the default weight of 1 per item.
+    /// The weights of items already in the cache are recalculated, and items are removed if the
+    /// cache is now over its maximum weight.
+    pub fn set_weigher<F>(&self, weigher: F)
+    where
+        F: Fn(&K, &V) -> usize + Send + Sync + 'static,
+    {
+        self.with_lock(|cache| (cache.set_weigher(weigher), None))
+    }
+
+    /// Change the maximum number of items that can be stored in the cache at once. If more items
+    /// than this are currently stored, the least recently requested items are removed
+    /// immediately.
+    pub fn set_max_entries(&self, max_entries: usize) {
+        self.with_lock(|cache| (cache.set_max_entries(max_entries), None))
+    }
+
+    /// Change the number of recent requests a key needs before its value is stored in the cache.
+    pub fn set_admit_threshold(&self, threshold: u32) {
+        self.with_lock(|cache| (cache.set_admit_threshold(threshold), None))
+    }
+
+    /// Change the length of the cache's recent request memory. Some contents of the cache may be
+    /// removed immediately if the new memory length is shorter than the old memory length.
+    pub fn set_mem_len(&self, new_len: usize) {
+        self.with_lock(|cache| (cache.set_mem_len(new_len), None))
+    }
+
+    /// Tune the memory length automatically, keeping it within the given bounds, or stop tuning
+    /// it with `None`. See [`DynamicCacheLocal::set_auto_mem_len`].
+    pub fn set_auto_mem_len(&self, bounds: Option<RangeInclusive<usize>>) {
+        self.with_lock(|cache| (cache.set_auto_mem_len(bounds), None))
+    }
+
+    /// Set a function to be called with every stored value that leaves the cache, along with its
+    /// key and the reason it was removed. It's only called after the cache has been unlocked.
+    pub fn set_eviction_listener<F>(&self, listener: F)
+    where
+        F: Fn(K, Arc<V>, EvictionReason) + Send + Sync + 'static,
+    {
+        self.with_lock(|cache| (cache.set_eviction_listener(listener), None))
+    }
+
+    /// Get a snapshot of all values stored in the cache, in arbitrary order.
+    pub fn entries(&self) -> Vec<(K, Arc<V>)> {
+        let stored = self.inner.stored.read().unwrap();
+        let values = &stored.values;
+        values.iter().map(|(k, v)| (k.clone(), v.clone())).collect()
+    }
+
+    /// Clear out all stored values and all memory in the cache.
+    pub fn clear_cache(&self) {
+        self.with_lock(|cache| (cache.clear_cache(), None))
+    }
+
+    /// Get the cache metrics as a pair `(hits, misses)`, after applying all buffered requests.
+    pub fn hits_misses(&self) -> (u64, u64) {
+        self.with_lock(|cache| ((cache.hits(), cache.misses()), None))
+    }
+
+    /// Get a snapshot of the cache's statistics, after applying all buffered requests.
+    pub fn stats(&self) -> CacheStats {
+        self.with_lock(|cache| (cache.stats(), None))
+    }
+
+    /// Reset all of the cache's metrics, including for any hits not yet applied.
+    pub fn reset_metrics(&self) {
+        self.with_lock(|cache| (cache.reset_metrics(), None))
+    }
+}
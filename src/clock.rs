@@ -0,0 +1,21 @@
+//! Time sources for the cache's time-based features.
+
+use std::time::Instant;
+
+/// A source of the current time, used by the cache for anything time-based. The cache uses
+/// [`SystemClock`] unless given a different one, which is mostly useful for testing code that
+/// relies on time-based expiry without having to sleep.
+pub trait Clock: Send + Sync {
+    /// Get the current time.
+    fn now(&self) -> Instant;
+}
+
+/// A [`Clock`] reading the system's monotonic clock, via [`Instant::now`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
@@ -0,0 +1,162 @@
+//! The entry API for [`DynamicCacheLocal`].
+
+use crate::{DynamicCacheLocal, SharedPtr};
+use std::hash::{BuildHasher, Hash};
+use std::sync::Arc;
+
+/// A view into a single key's entry in a [`DynamicCacheLocal`], returned by
+/// [`DynamicCacheLocal::entry`]. Getting the entry counts as a request for the key, and the variant
+/// describes the key's state just before that request.
+pub enum Entry<'a, K, V, S, P: SharedPtr<V> = Arc<V>> {
+    /// A value is stored for the key.
+    Occupied(OccupiedEntry<'a, K, V, S, P>),
+    /// No value is stored, but the key has been requested before and is still in the cache's
+    /// recent request memory.
+    Tracked(TrackedEntry<'a, K, V, S, P>),
+    /// No value is stored, and this is the only request for the key in recent memory.
+    Vacant(VacantEntry<'a, K, V, S, P>),
+}
+
+impl<'a, K: Clone + Eq + Hash, V, S: BuildHasher, P: SharedPtr<V>> Entry<'a, K, V, S, P> {
+    /// Get the key of this entry.
+    pub fn key(&self) -> &K {
+        match self {
+            Entry::Occupied(e) => e.key(),
+            Entry::Tracked(e) => e.key(),
+            Entry::Vacant(e) => e.key(),
+        }
+    }
+
+    /// Get the stored value, or insert `default` following the same rules as
+    /// [`DynamicCacheLocal::insert`]. The value is only stored if the key has been requested
+    /// enough times.
+    pub fn or_insert(self, default: V) -> P {
+        self.or_insert_with(|| default)
+    }
+
+    /// Get the stored value, or insert the result of `f` following the same rules as
+    /// [`DynamicCacheLocal::insert`]. The value is only stored if the key has been requested
+    /// enough times.
+    pub fn or_insert_with<F: FnOnce() -> V>(self, f: F) -> P {
+        match self {
+            Entry::Occupied(e) => e.value,
+            Entry::Tracked(e) => e.insert(f()),
+            Entry::Vacant(e) => e.insert(f()),
+        }
+    }
+
+    /// Modify the stored value, if there is one, before any further use of the entry.
+    ///
+    /// If `f` swaps in a different pointer, it replaces the stored value just like
+    /// [`OccupiedEntry::insert`]: the old value is reported to the eviction listener as replaced,
+    /// and the new one is weighed, but keeps the old one's expiration time. If `f` leaves the
+    /// pointer alone, the cache isn't touched.
+    pub fn and_modify<F: FnOnce(&mut P)>(self, f: F) -> Self {
+        match self {
+            Entry::Occupied(mut e) => {
+                let mut value = e.value.clone();
+                f(&mut value);
+                if !P::ptr_eq(&value, &e.value) {
+                    e.replace(value);
+                }
+                Entry::Occupied(e)
+            }
+            e => e,
+        }
+    }
+}
+
+/// A view into an entry with a stored value. Part of the [`Entry`] enum.
+pub struct OccupiedEntry<'a, K, V, S, P: SharedPtr<V> = Arc<V>> {
+    pub(crate) cache: &'a mut DynamicCacheLocal<K, V, S, P>,
+    pub(crate) key: K,
+    pub(crate) value: P,
+}
+
+impl<'a, K: Clone + Eq + Hash, V, S: BuildHasher, P: SharedPtr<V>> OccupiedEntry<'a, K, V, S, P> {
+    /// Get the key of this entry.
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    /// Get the stored value.
+    pub fn get(&self) -> &P {
+        &self.value
+    }
+
+    /// Replace the stored value, returning the old one. The new value gets the cache's default
+    /// time-to-live. If it's too heavy to fit in the cache, the old value is still removed.
+    pub fn insert(&mut self, value: V) -> P {
+        let expires = self
+            .cache
+            .ttl
+            .and_then(|ttl| self.cache.clock.now().checked_add(ttl));
+        let value = P::new(value);
+        self.cache.store(&self.key, value.clone(), expires);
+        std::mem::replace(&mut self.value, value)
+    }
+
+    /// Replace the stored value, keeping its expiration time.
+    fn replace(&mut self, value: P) {
+        let expires = self.cache.map.get(&self.key).and_then(|r| r.expires);
+        self.cache.store(&self.key, value.clone(), expires);
+        self.value = value;
+    }
+
+    /// Remove the stored value from the cache, exactly like [`DynamicCacheLocal::pop`]. The
+    /// request history for the key is kept.
+    pub fn remove(self) -> P {
+        self.cache.pop(&self.key);
+        self.value
+    }
+}
+
+/// A view into an entry without a stored value, whose key has been requested before. Part of the
+/// [`Entry`] enum.
+pub struct TrackedEntry<'a, K, V, S, P: SharedPtr<V> = Arc<V>> {
+    pub(crate) cache: &'a mut DynamicCacheLocal<K, V, S, P>,
+    pub(crate) key: K,
+    pub(crate) recent: u32,
+    pub(crate) admit: bool,
+}
+
+impl<'a, K: Clone + Eq + Hash, V, S: BuildHasher, P: SharedPtr<V>> TrackedEntry<'a, K, V, S, P> {
+    /// Get the key of this entry.
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    /// Get the number of requests for the key in the cache's recent request memory, including the
+    /// one made by getting this entry.
+    pub fn recent_request_count(&self) -> u32 {
+        self.recent
+    }
+
+    /// Insert a value following the same rules as [`DynamicCacheLocal::insert`]. The value is only
+    /// stored if the key has been requested enough times.
+    pub fn insert(self, value: V) -> P {
+        let ttl = self.cache.ttl;
+        self.cache.insert_new(&self.key, value, self.admit, ttl)
+    }
+}
+
+/// A view into an entry for a key that hasn't been requested before. Part of the [`Entry`] enum.
+pub struct VacantEntry<'a, K, V, S, P: SharedPtr<V> = Arc<V>> {
+    pub(crate) cache: &'a mut DynamicCacheLocal<K, V, S, P>,
+    pub(crate) key: K,
+    pub(crate) admit: bool,
+}
+
+impl<'a, K: Clone + Eq + Hash, V, S: BuildHasher, P: SharedPtr<V>> VacantEntry<'a, K, V, S, P> {
+    /// Get the key of this entry.
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    /// Insert a value following the same rules as [`DynamicCacheLocal::insert`]. The value is only
+    /// stored if the cache's [admission threshold](DynamicCacheLocal::admit_threshold) is 1.
+    pub fn insert(self, value: V) -> P {
+        let ttl = self.cache.ttl;
+        self.cache.insert_new(&self.key, value, self.admit, ttl)
+    }
+}
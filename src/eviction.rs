@@ -0,0 +1,114 @@
+//! Reporting of values removed from the cache.
+
+use crate::stats::EvictionCounts;
+use std::sync::Arc;
+
+/// Why a value was removed from the cache, as reported to an eviction listener.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum EvictionReason {
+    /// All requests for the key fell out of the cache's recent request memory.
+    Forgotten,
+    /// The value outlived its time-to-live. Expired values are only noticed when they're next
+    /// requested or inserted; one removed for any other reason before that is reported with that
+    /// reason instead.
+    Expired,
+    /// The value was explicitly removed with `pop`.
+    Removed,
+    /// A new value was stored for the same key.
+    Replaced,
+    /// The request memory was shortened, and all requests for the key were forgotten as a result.
+    Resized,
+    /// The whole cache was cleared.
+    Cleared,
+    /// The value was dropped to keep the cache within its maximum weight or entry count.
+    Capacity,
+}
+
+/// A function called with every value removed from the cache.
+pub(crate) type EvictionListener<K, P> = Arc<dyn Fn(K, P, EvictionReason) + Send + Sync>;
+
+/// Where the cache reports values it removes.
+pub(crate) struct Evictions<K, P> {
+    /// How many values have been removed for each reason.
+    pub(crate) counts: EvictionCounts,
+    listener: Option<EvictionListener<K, P>>,
+    /// Removed values waiting to be passed to the listener. This is only used by shared caches,
+    /// which must release their lock before calling the listener.
+    deferred: Option<Vec<(K, P, EvictionReason)>>,
+    /// Keep removed values even without a listener, for caches that need to know about them.
+    collect: bool,
+}
+
+impl<K, P> Evictions<K, P> {
+    pub(crate) fn new() -> Self {
+        Self {
+            counts: EvictionCounts::default(),
+            listener: None,
+            deferred: None,
+            collect: false,
+        }
+    }
+
+    pub(crate) fn set_listener(&mut self, listener: EvictionListener<K, P>) {
+        self.listener = Some(listener);
+    }
+
+    /// Hold on to removed values until [`take_deferred`](Self::take_deferred) is called, instead
+    /// of calling the listener right away.
+    pub(crate) fn defer(&mut self) {
+        self.deferred = Some(Vec::new());
+    }
+
+    /// Defer reporting, and hold on to removed values even if there's no listener.
+    pub(crate) fn collect(&mut self) {
+        self.defer();
+        self.collect = true;
+    }
+
+    /// Report a removed value. The key is only built if something is going to use it.
+    pub(crate) fn report(&mut self, key: impl FnOnce() -> K, value: P, reason: EvictionReason) {
+        self.counts.record(reason);
+        if self.listener.is_none() && !self.collect {
+            return;
+        }
+        match (&mut self.deferred, &self.listener) {
+            (Some(deferred), _) => deferred.push((key(), value, reason)),
+            (None, Some(listener)) => listener(key(), value, reason),
+            (None, None) => (),
+        }
+    }
+
+    /// Take all deferred removals, to be reported once the cache is unlocked.
+    pub(crate) fn take_deferred(&mut self) -> Option<Deferred<K, P>> {
+        let deferred = self.deferred.as_mut()?;
+        if deferred.is_empty() {
+            return None;
+        }
+        Some(Deferred {
+            listener: self.listener.clone(),
+            evicted: std::mem::take(deferred),
+        })
+    }
+}
+
+/// Removed values that still need to be passed to the listener, if there is one.
+pub(crate) struct Deferred<K, P> {
+    listener: Option<EvictionListener<K, P>>,
+    evicted: Vec<(K, P, EvictionReason)>,
+}
+
+impl<K, P> Deferred<K, P> {
+    /// Get the keys of all removed values, in the order they were removed.
+    pub(crate) fn keys(&self) -> impl Iterator<Item = &K> {
+        self.evicted.iter().map(|(k, _, _)| k)
+    }
+
+    pub(crate) fn report(self) {
+        let Some(listener) = self.listener else {
+            return;
+        };
+        for (k, v, reason) in self.evicted {
+            listener(k, v, reason);
+        }
+    }
+}
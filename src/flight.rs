@@ -0,0 +1,160 @@
+//! Bookkeeping for loads that are in progress, so that concurrent requests for the same key can
+//! share a single call to the loading function.
+
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use std::task::Waker;
+#[cfg(feature = "async")]
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// All in-progress loads for a cache, by key.
+pub(crate) type InFlight<K, V> = Mutex<HashMap<K, Arc<Flight<V>>>>;
+
+#[derive(Debug)]
+enum FlightState<V> {
+    /// Still loading. Holds the wakers of any async requests waiting on the load.
+    Loading(Vec<Waker>),
+    Done(Arc<V>),
+    Abandoned,
+}
+
+/// A single in-progress load that other requests can wait on.
+#[derive(Debug)]
+pub(crate) struct Flight<V> {
+    state: Mutex<FlightState<V>>,
+    cond: Condvar,
+}
+
+impl<V> Flight<V> {
+    fn new() -> Self {
+        Self {
+            state: Mutex::new(FlightState::Loading(Vec::new())),
+            cond: Condvar::new(),
+        }
+    }
+
+    fn finish(&self, state: FlightState<V>) {
+        let old = std::mem::replace(
+            &mut *self.state.lock().unwrap_or_else(PoisonError::into_inner),
+            state,
+        );
+        self.cond.notify_all();
+        if let FlightState::Loading(wakers) = old {
+            wakers.into_iter().for_each(Waker::wake);
+        }
+    }
+
+    /// Block until the load finishes. Returns `None` if the load was abandoned, in which case the
+    /// caller should try again.
+    pub(crate) fn wait(&self) -> Option<Arc<V>> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            match &*state {
+                FlightState::Loading(_) => state = self.cond.wait(state).unwrap(),
+                FlightState::Done(v) => return Some(v.clone()),
+                FlightState::Abandoned => return None,
+            }
+        }
+    }
+}
+
+/// Wait for a load to finish without blocking the thread. Resolves to `None` if the load was
+/// abandoned, in which case the caller should try again.
+#[cfg(feature = "async")]
+pub(crate) struct FlightWait<V> {
+    flight: Arc<Flight<V>>,
+}
+
+#[cfg(feature = "async")]
+impl<V> FlightWait<V> {
+    pub(crate) fn new(flight: Arc<Flight<V>>) -> Self {
+        Self { flight }
+    }
+}
+
+#[cfg(feature = "async")]
+impl<V> Future for FlightWait<V> {
+    type Output = Option<Arc<V>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.flight.state.lock().unwrap();
+        match &mut *state {
+            FlightState::Loading(wakers) => {
+                if !wakers.iter().any(|w| w.will_wake(cx.waker())) {
+                    wakers.push(cx.waker().clone());
+                }
+                Poll::Pending
+            }
+            FlightState::Done(v) => Poll::Ready(Some(v.clone())),
+            FlightState::Abandoned => Poll::Ready(None),
+        }
+    }
+}
+
+/// Either a load to wait on, or the responsibility to perform one.
+pub(crate) enum Role<'a, K: Eq + Hash, V> {
+    Follower(Arc<Flight<V>>),
+    Leader(Leader<'a, K, V>),
+}
+
+/// Join the in-progress load for `key`, or start a new one if there isn't one yet. `map` must be
+/// the locked contents of `loading`.
+pub(crate) fn join_or_lead<'a, K, V, Q>(
+    loading: &'a InFlight<K, V>,
+    map: &mut HashMap<K, Arc<Flight<V>>>,
+    key: &Q,
+) -> Role<'a, K, V>
+where
+    K: Clone + Eq + Hash + Borrow<Q>,
+    Q: ?Sized + Hash + Eq + ToOwned<Owned = K>,
+{
+    if let Some(flight) = map.get(key) {
+        return Role::Follower(flight.clone());
+    }
+    let key = key.to_owned();
+    let flight = Arc::new(Flight::new());
+    map.insert(key.clone(), flight.clone());
+    Role::Leader(Leader {
+        loading,
+        key: Some(key),
+        flight,
+    })
+}
+
+/// The request responsible for performing a load. If it is dropped without calling
+/// [`finish`](Leader::finish), such as when the loading function panics or an async load is
+/// cancelled, the load is abandoned and all waiting requests are woken up to try again.
+pub(crate) struct Leader<'a, K: Eq + Hash, V> {
+    loading: &'a InFlight<K, V>,
+    key: Option<K>,
+    flight: Arc<Flight<V>>,
+}
+
+impl<K: Eq + Hash, V> Leader<'_, K, V> {
+    /// Hand the loaded value to all waiting requests. `map` must be the locked contents of the
+    /// in-flight map this leader came from.
+    pub(crate) fn finish(mut self, map: &mut HashMap<K, Arc<Flight<V>>>, value: Arc<V>) {
+        if let Some(key) = self.key.take() {
+            map.remove(&key);
+        }
+        self.flight.finish(FlightState::Done(value));
+    }
+}
+
+impl<K: Eq + Hash, V> Drop for Leader<'_, K, V> {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            self.loading
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .remove(&key);
+            self.flight.finish(FlightState::Abandoned);
+        }
+    }
+}
@@ -13,355 +13,3561 @@
 //!
 //! Sure, a fixed size cache that stores "seen once" items would also work, but
 //! the memory usage would be higher than really necessary. Hence, this crate.
+//!
+//! ## Cargo Features
+//!
+//! - `async`: Adds `DynamicCache::get_or_insert_async`, for loading values with a future. It
+//!   doesn't depend on any particular async runtime.
+//! - `serde`: Adds `CacheSnapshot`, a serializable copy of a cache's state that can be restored
+//!   into a new cache, for keeping a warm cache across restarts.
 
 use std::sync::Mutex;
+
+mod buffered;
+mod clock;
+mod entry;
+mod eviction;
+mod fingerprint;
+mod flight;
+mod pointer;
+mod rc;
+mod sharded;
+mod sketch;
+#[cfg(feature = "serde")]
+mod snapshot;
+mod stats;
+mod tuning;
+mod weak;
+
+pub use buffered::BufferedDynamicCache;
+pub use clock::{Clock, SystemClock};
+pub use entry::{Entry, OccupiedEntry, TrackedEntry, VacantEntry};
+pub use eviction::EvictionReason;
+use eviction::Evictions;
+pub use fingerprint::FingerprintCacheLocal;
+use flight::{InFlight, Role};
+pub use pointer::SharedPtr;
+pub use rc::RcDynamicCacheLocal;
+pub use sharded::ShardedDynamicCache;
+use sketch::SketchHistory;
+#[cfg(feature = "serde")]
+pub use snapshot::{CacheSnapshot, SnapshotError};
+pub use stats::{CacheStats, EvictionCounts};
+use std::borrow::Borrow;
 use std::collections::hash_map::RandomState;
 use std::collections::{HashMap, VecDeque};
 use std::fmt;
-use std::hash::Hash;
+use std::hash::{BuildHasher, Hash};
+use std::mem;
+use std::ops::RangeInclusive;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tuning::MemTuner;
+use weak::WeakValues;
+
+/// The memory length used by caches created through [`Default`].
+pub const DEFAULT_MEM_LEN: usize = 128;
+
+/// The number of recent requests a key needs before its value is stored, unless changed with
+/// [`DynamicCacheLocal::set_admit_threshold`].
+pub const DEFAULT_ADMIT_THRESHOLD: u32 = 2;
+
+/// Everything the cache knows about a single recently requested key.
+struct Record<P> {
+    /// Running request count for the key, used to identify its most recent request in the memory
+    /// queue.
+    counter: u32,
+    /// Number of requests for the key that are still in the memory queue.
+    recent: u32,
+    /// The stored value, if the key has been admitted into the cache.
+    value: Option<P>,
+    /// The weight of the stored value, as determined by the cache's weigher.
+    weight: usize,
+    /// When the stored value expires, if it has a time-to-live.
+    expires: Option<Instant>,
+    /// Whether the stored value is pinned, keeping the record around even with no requests left
+    /// in the memory queue.
+    pinned: bool,
+}
+
+/// Check if a key has been requested enough times for its value to be stored, given the requests
+/// for it still in memory and its running request counter.
+///
+/// The default threshold of 2 keeps the cache's original rule, storing a value on any second
+/// request for its key while the key is remembered, even if the first request has since left the
+/// memory. Any other threshold only counts requests still in memory.
+pub(crate) fn admits(threshold: u32, recent: u32, counter: u32) -> bool {
+    recent >= threshold || (threshold == 2 && counter != 0)
+}
+
+impl<P> Record<P> {
+    /// Check if the stored value has outlived its time-to-live.
+    fn expired(&self, clock: &dyn Clock) -> bool {
+        self.expires.is_some_and(|at| at <= clock.now())
+    }
+}
+
+/// A function giving the weight of a key-value pair, as used by
+/// [`DynamicCacheLocal::set_weigher`].
+type Weigher<K, V> = Box<dyn Fn(&K, &V) -> usize + Send + Sync>;
 
 /// A cache that will only hold onto items that have been requested more than
 /// once in recent memory. Single-use items are not held at all. Once an item is
 /// requested twice, it is cached until all memory of seeing a request has
 /// expired. The length of the memory is adjustable, and must be set at
 /// initialization.
-pub struct DynamicCacheLocal<K, V, S = RandomState> {
-    map: HashMap<K, (u32, Option<Arc<V>>), S>,
-    list: VecDeque<(K, u32)>,
+///
+/// The number of recent requests needed before an item is cached can be changed with
+/// [`set_admit_threshold`](Self::set_admit_threshold).
+///
+/// The total size of the stored values can optionally be capped by giving the cache a
+/// [weigher](Self::set_weigher) and a [maximum weight](Self::set_max_weight), and the number of
+/// stored values can be capped with [`set_max_entries`](Self::set_max_entries), independently of
+/// the memory length. When storing a value would go over either limit, the least recently
+/// requested values are dropped to make room.
+///
+/// Stored values can also be given a [time-to-live](Self::set_ttl). Once it has passed, the
+/// value is treated as missing, but the cache still remembers the requests for it, so a reloaded
+/// value is stored again immediately.
+///
+/// Values that must always be available can be [pinned](Self::pin), which stores them right away
+/// and keeps them no matter how they're requested.
+///
+/// Besides its length, the request memory can also be limited to a
+/// [span of time](Self::set_mem_duration), so that requests are forgotten once they're old enough,
+/// no matter how many other requests were made in between.
+///
+/// An [eviction listener](Self::set_eviction_listener) can be set to find out whenever a stored
+/// value leaves the cache, and why.
+///
+/// For very long memories, a cache created with [`with_sketch`](Self::with_sketch) keeps an
+/// approximate count of requests instead of remembering each one, which takes far less space.
+///
+/// Rather than picking a memory length up front, it can be [tuned
+/// automatically](Self::set_auto_mem_len) within some bounds, based on how the cache is used.
+///
+/// Values are handed out in the [pointer type](SharedPtr) `P`, which is `Arc<V>` for every cache
+/// created here. [`RcDynamicCacheLocal`] is this same cache with `Rc<V>` instead.
+pub struct DynamicCacheLocal<K, V, S = RandomState, P: SharedPtr<V> = Arc<V>> {
+    map: HashMap<K, Record<P>, S>,
+    /// The recent request memory, newest first. Each request holds the key's request count at the
+    /// time, and when it was made if the memory is time-limited.
+    list: VecDeque<(K, u32, Option<Instant>)>,
     mem_len: usize,
+    mem_duration: Option<Duration>,
+    admit_threshold: u32,
+    weigher: Option<Weigher<K, V>>,
+    max_weight: usize,
+    weight: usize,
+    max_entries: usize,
+    ttl: Option<Duration>,
+    clock: Box<dyn Clock>,
+    evictions: Evictions<K, P>,
+    /// Request counts for all keys, if the cache only tracks keys requested often enough.
+    sketch: Option<Box<SketchHistory>>,
+    /// Adjusts the memory length, if it's tuned automatically.
+    tuner: Option<Box<MemTuner>>,
+    /// Values that left the cache but are still alive elsewhere, if weak values are kept.
+    weak: Option<WeakValues<K, V, P, S>>,
+    /// Number of records kept only for a pinned value, with no requests in the memory queue.
+    idle_pins: usize,
     size: usize,
     hits: u64,
     misses: u64,
+    admissions: u64,
+    rejections: u64,
+    pops: u64,
+    resurrections: u64,
+}
+
+impl<K: Clone + Eq + Hash, V> DynamicCacheLocal<K, V> {
+    /// Create and initialize a new cache.
+    pub fn new(mem_len: usize) -> Self {
+        Self::with_hasher(mem_len, RandomState::new())
+    }
+
+    /// Create and initialize a new cache, with space for at least `capacity` tracked keys
+    /// allocated up front.
+    pub fn with_capacity(mem_len: usize, capacity: usize) -> Self {
+        Self::with_capacity_and_hasher(mem_len, capacity, RandomState::new())
+    }
+
+    /// Create and initialize a new cache that counts requests with a compact sketch, instead of
+    /// remembering each one. See [`with_sketch_and_hasher`](Self::with_sketch_and_hasher).
+    pub fn with_sketch(mem_len: usize) -> Self {
+        Self::with_sketch_and_hasher(mem_len, RandomState::new())
+    }
 }
 
-impl<K: Clone + Eq + Hash, V, S> DynamicCacheLocal<K, V, S> {
+impl<K: Clone + Eq + Hash, V, S: BuildHasher> DynamicCacheLocal<K, V, S> {
     /// Create and initialize a new cache, using the given hash builder to hash keys. The same
     /// warnings given for [`HashMap::with_hasher`] apply here.
     pub fn with_hasher(mem_len: usize, hash_builder: S) -> DynamicCacheLocal<K, V, S> {
-        // Just make it work if an invalid value is thrown in
-        let mem_len = mem_len.clamp(2, u32::MAX as usize);
+        Self::with_capacity_and_hasher(mem_len, 0, hash_builder)
+    }
 
-        Self {
-            map: HashMap::with_hasher(hash_builder),
-            list: VecDeque::with_capacity(mem_len),
-            mem_len,
-            size: 0,
-            hits: 0,
-            misses: 0,
-        }
+    /// Create and initialize a new cache with space for at least `capacity` tracked keys, using
+    /// the given hash builder to hash keys. The same warnings given for
+    /// [`HashMap::with_capacity_and_hasher`] apply here.
+    pub fn with_capacity_and_hasher(
+        mem_len: usize,
+        capacity: usize,
+        hash_builder: S,
+    ) -> DynamicCacheLocal<K, V, S> {
+        Self::create(mem_len, capacity, hash_builder)
+    }
+
+    /// Create and initialize a new cache that counts requests with a compact sketch, instead of
+    /// remembering each one, using the given hash builder to hash keys.
+    ///
+    /// Every request is counted in a count-min sketch, which uses about 3 bytes per request of
+    /// memory length no matter how many different keys are requested. Only keys that have been
+    /// requested enough times to be admitted, or that have a stored value, are tracked
+    /// individually, so single-use keys cost nothing beyond their share of the sketch. Stored
+    /// values are still forgotten once all of their requests are more than
+    /// [`mem_len`](Self::mem_len) requests old.
+    ///
+    /// Counts from the sketch are approximate: they can be too high when keys collide, which may
+    /// admit a value early, and they fade out gradually rather than dropping off once a request
+    /// leaves the memory. A key's requests count towards admission for between two thirds and
+    /// four thirds of a memory length of requests, so about as many values are admitted as
+    /// without a sketch. They're capped at 16, so an [admission
+    /// threshold](Self::set_admit_threshold) above that admits nothing. The sketch is sized for
+    /// the initial memory length and doesn't change with [`set_mem_len`](Self::set_mem_len).
+    pub fn with_sketch_and_hasher(mem_len: usize, hash_builder: S) -> DynamicCacheLocal<K, V, S> {
+        Self::create_with_sketch(mem_len, hash_builder)
     }
 }
 
-impl<K: Clone + Eq + Hash, V> DynamicCacheLocal<K, V> {
-    /// Create and initialize a new cache.
-    pub fn new(mem_len: usize) -> Self {
+impl<K: Clone + Eq + Hash, V, S: BuildHasher, P: SharedPtr<V>> DynamicCacheLocal<K, V, S, P> {
+    /// Create a cache handing out values in any kind of pointer. The public constructors each
+    /// settle on one kind, so it never has to be named.
+    pub(crate) fn create(mem_len: usize, capacity: usize, hash_builder: S) -> Self {
         // Just make it work if an invalid value is thrown in
         let mem_len = mem_len.clamp(2, u32::MAX as usize);
 
         Self {
-            map: HashMap::new(),
+            map: HashMap::with_capacity_and_hasher(capacity, hash_builder),
             list: VecDeque::with_capacity(mem_len),
             mem_len,
+            mem_duration: None,
+            admit_threshold: DEFAULT_ADMIT_THRESHOLD,
+            weigher: None,
+            max_weight: usize::MAX,
+            weight: 0,
+            max_entries: usize::MAX,
+            ttl: None,
+            clock: Box::new(SystemClock),
+            evictions: Evictions::new(),
+            sketch: None,
+            tuner: None,
+            weak: None,
+            idle_pins: 0,
             size: 0,
             hits: 0,
             misses: 0,
+            admissions: 0,
+            rejections: 0,
+            pops: 0,
+            resurrections: 0,
         }
     }
 
+    /// Create a cache handing out values in any kind of pointer, that counts requests with a
+    /// sketch.
+    pub(crate) fn create_with_sketch(mem_len: usize, hash_builder: S) -> Self {
+        let mut cache = Self::create(mem_len, 0, hash_builder);
+        cache.sketch = Some(Box::new(SketchHistory::new(cache.mem_len)));
+        cache
+    }
+
     /// Attempt to retrieve a value from the cache. This updates the cache's memory of what values
     /// have been requested.
-    pub fn get(&mut self, key: &K) -> Option<Arc<V>> {
-        let (counter, ret) = match self.map.get_mut(key) {
-            Some((counter, Some(v))) => {
-                *counter += 1;
-                (*counter, Some(v.clone()))
+    ///
+    /// The key may be any borrowed form of the cache's key type, but [`Hash`] and [`Eq`] on the
+    /// borrowed form *must* match those for the key type.
+    pub fn get<Q>(&mut self, key: &Q) -> Option<P>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq + ToOwned<Owned = K>,
+    {
+        self.request(key).0
+    }
+
+    /// Record a request for a key, exactly like [`get`](Self::get). Along with the stored value,
+    /// this returns the key's [recent request count](Self::recent_request_count) including this
+    /// request, and whether a value inserted for the key right now would be admitted.
+    fn request<Q>(&mut self, key: &Q) -> (Option<P>, u32, bool)
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq + ToOwned<Owned = K>,
+    {
+        let mut estimate = None;
+        if let Some(sketch) = &mut self.sketch {
+            let hash = self.map.hasher().hash_one(key);
+            sketch.record(hash);
+            sketch.requests += 1;
+            let count = sketch.estimate(hash);
+            if !self.map.contains_key(key)
+                && count < self.admit_threshold
+                && !self.weak.as_ref().is_some_and(|weak| weak.contains(key))
+            {
+                // Not worth tracking yet, but the sketch remembers the request
+                if let Some(tuner) = &mut self.tuner {
+                    tuner.untracked(hash);
+                }
+                self.forget_distant(EvictionReason::Forgotten);
+                self.misses += 1;
+                self.tune(false);
+                return (None, count, false);
             }
-            Some((counter, None)) => {
-                *counter += 1;
-                (*counter, None)
+            estimate = Some(count);
+        }
+
+        let owned = key.to_owned();
+        let (counter, recent, ret) = match self.map.get_mut(key) {
+            Some(entry) => {
+                if entry.recent == 0 {
+                    self.idle_pins -= 1;
+                }
+                entry.counter = entry.counter.wrapping_add(1);
+                entry.recent += 1;
+                let (counter, recent) = (entry.counter, entry.recent);
+                if entry.value.is_some() && entry.expired(&*self.clock) {
+                    self.remove_value(key, EvictionReason::Expired);
+                    (counter, recent, None)
+                } else {
+                    (counter, recent, entry.value.clone())
+                }
             }
             None => {
-                self.map.insert(key.clone(), (0, None));
-                (0, None)
+                if let Some(tuner) = &mut self.tuner {
+                    tuner.untracked(self.map.hasher().hash_one(key));
+                }
+                let entry = Record {
+                    counter: 0,
+                    recent: 1,
+                    value: None,
+                    weight: 0,
+                    expires: None,
+                    pinned: false,
+                };
+                self.map.insert(owned.clone(), entry);
+                (0, 1, None)
             }
         };
 
-        if self.list.len() == self.mem_len {
-            let (key, last_count) = self
-                .list
-                .pop_back()
-                .expect("Cache memory queue should be non-empty at this point");
-            let (counter, val) = self
-                .map
-                .get(&key)
-                .expect("Cache hashmap should contain the key from the memory queue");
-            if *counter == last_count {
-                if val.is_some() {
-                    self.size -= 1;
-                }
-                self.map.remove(&key);
-            }
-        }
-        self.list.push_front((key.clone(), counter));
+        let recent = recent - self.push_request(owned, counter);
+        let ret = ret.or_else(|| self.resurrect(key));
 
         if ret.is_some() {
             self.hits += 1;
         } else {
             self.misses += 1;
         }
+        self.tune(ret.is_some());
 
-        ret
+        // With a sketch, keys are only tracked once they've been requested enough times
+        let admit = estimate.is_some() || admits(self.admit_threshold, recent, counter);
+        (ret, estimate.unwrap_or(recent), admit)
+    }
+
+    /// Store a value that has left the cache again, if it's still alive elsewhere.
+    fn resurrect<Q>(&mut self, key: &Q) -> Option<P>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let v = self.weak.as_mut()?.take(key)?;
+        let expires = self.ttl.and_then(|ttl| self.clock.now().checked_add(ttl));
+        self.store(key, v.clone(), expires);
+        self.resurrections += 1;
+        Some(v)
+    }
+
+    /// Add a request for a key to the front of the memory queue, forgetting old requests to make
+    /// room. The key's record must already count the request, with `counter` as its new counter.
+    /// Returns how many of the key's own earlier requests were forgotten.
+    fn push_request(&mut self, key: K, counter: u32) -> u32 {
+        let now = self.mem_duration.map(|_| self.clock.now());
+        let mut forgotten = 0;
+        while let Some((oldest, _, _)) = self.list.back() {
+            let own = *oldest == key;
+            if !(self.oldest_is_stale(now)
+                || self.oldest_is_distant()
+                || self.list.len() == self.mem_len)
+            {
+                break;
+            }
+            forgotten += u32::from(own);
+            self.forget_oldest(EvictionReason::Forgotten);
+        }
+        self.list.push_front((key, counter, now));
+        if let Some(sketch) = &mut self.sketch {
+            sketch.positions.push_front(sketch.requests);
+        }
+        forgotten
     }
 
     /// Attempt to remove a value from the cache.
     ///
-    /// This will remove the value itself from the cache, but doesn't change the cache's stored 
-    /// request history. This means that any new [`get_or_insert`] calls will re-load the value 
-    /// into the cache.
-    pub fn pop(&mut self, key: &K) -> Option<Arc<V>> {
-        let Some((_, v)) = self.map.get_mut(key) else { return None };
-        v.take()
+    /// This will remove the value itself from the cache, but doesn't change the cache's stored
+    /// request history. This means that any new [`get_or_insert`] calls will re-load the value
+    /// into the cache. A [pinned](Self::pin) value is unpinned as it's removed.
+    ///
+    /// The key may be any borrowed form of the cache's key type, but [`Hash`] and [`Eq`] on the
+    /// borrowed form *must* match those for the key type.
+    pub fn pop<Q>(&mut self, key: &Q) -> Option<P>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.pops += 1;
+        self.remove_value(key, EvictionReason::Removed)
+    }
+
+    /// Take the stored value for a key out of the cache, reporting it to the eviction listener.
+    /// The key's request history is left alone.
+    fn remove_value<Q>(&mut self, key: &Q, reason: EvictionReason) -> Option<P>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let entry = self.map.get_mut(key)?;
+        let v = entry.value.take()?;
+        self.size -= 1;
+        self.weight -= entry.weight;
+        // The value's pin goes with it, along with its record if that was all that kept it
+        let idle = mem::take(&mut entry.pinned) && entry.recent == 0;
+        let map = &self.map;
+        self.evictions.report(
+            || map.get_key_value(key).unwrap().0.clone(),
+            v.clone(),
+            reason,
+        );
+        if idle {
+            self.map.remove(key);
+            self.idle_pins -= 1;
+        }
+        Some(v)
     }
 
     /// Insert a value into the cache. If the value is already present, an `Arc<V>` of the stored
-    /// value is returned. If the value is not stored but has been requested more than once, then
-    /// it is stored and returned. If the value is not stored and hasn't been requested more than
-    /// once, it is not stored and is simply returned, wrapped as an `Arc<V>`.
-    pub fn insert(&mut self, key: &K, v: V) -> Arc<V> {
-        match self.map.get_mut(key) {
-            None | Some((0, _)) => Arc::new(v),
-            Some((_, Some(val))) => val.clone(),
-            Some((_, val @ None)) => {
-                let v = Arc::new(v);
-                *val = Some(v.clone());
-                self.size += 1;
-                v
+    /// value is returned. If the value is not stored but has been requested at least
+    /// [`admit_threshold`](Self::admit_threshold) times in recent memory, then it is stored and
+    /// returned. Otherwise, it is not stored and is simply returned, wrapped as an `Arc<V>`.
+    /// With the default threshold of 2, any second request counts as long as the key is still
+    /// remembered; see [`set_admit_threshold`](Self::set_admit_threshold).
+    ///
+    /// If storing the value would put the cache over its [maximum weight](Self::max_weight) or
+    /// [maximum entry count](Self::max_entries), the least recently requested values are removed
+    /// until it fits. Values weighing more than the maximum on their own are never stored.
+    ///
+    /// A stored value expires after the cache's default [time-to-live](Self::ttl), if it has one.
+    ///
+    /// The key may be any borrowed form of the cache's key type, but [`Hash`] and [`Eq`] on the
+    /// borrowed form *must* match those for the key type.
+    pub fn insert<Q>(&mut self, key: &Q, v: V) -> P
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.insert_inner(key, v, self.ttl)
+    }
+
+    /// Insert a value into the cache, exactly like [`insert`](Self::insert), except that a newly
+    /// stored value expires after `ttl` instead of the cache's default time-to-live.
+    ///
+    /// The key may be any borrowed form of the cache's key type, but [`Hash`] and [`Eq`] on the
+    /// borrowed form *must* match those for the key type.
+    pub fn insert_with_ttl<Q>(&mut self, key: &Q, v: V, ttl: Duration) -> P
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.insert_inner(key, v, Some(ttl))
+    }
+
+    fn insert_inner<Q>(&mut self, key: &Q, v: V, ttl: Option<Duration>) -> P
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        if self.mem_duration.is_some() {
+            self.forget_stale(Some(self.clock.now()), EvictionReason::Forgotten);
+        }
+        let Some(entry) = self.map.get(key) else {
+            self.rejections += 1;
+            return P::new(v);
+        };
+        if let Some(val) = &entry.value {
+            if !entry.expired(&*self.clock) {
+                return val.clone();
             }
         }
+        let (recent, counter) = (entry.recent, entry.counter);
+        // Any value still stored at this point has expired
+        self.remove_value(key, EvictionReason::Expired);
+        // With a sketch, keys are only tracked once they've been requested enough times
+        let admit = self.sketch.is_some() || admits(self.admit_threshold, recent, counter);
+        self.insert_new(key, v, admit, ttl)
     }
 
-    /// Fetch an item via the cache, potentially filling in the cache on a miss via the function
-    /// `f`.
-    pub fn get_or_insert<F: FnOnce() -> V>(&mut self, key: &K, f: F) -> Arc<V> {
-        self.get(key).unwrap_or_else(|| self.insert(key, f()))
+    /// Store a value for a key that has none, if `admit` says the key was requested enough times,
+    /// and count it as an admission or a rejection.
+    pub(crate) fn insert_new<Q>(&mut self, key: &Q, v: V, admit: bool, ttl: Option<Duration>) -> P
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let v = P::new(v);
+        let expires = ttl.and_then(|ttl| self.clock.now().checked_add(ttl));
+        if admit && self.store(key, v.clone(), expires) {
+            self.admissions += 1;
+        } else {
+            self.rejections += 1;
+        }
+        v
     }
 
-    /// Get the number of items currently stored in the cache.
-    pub fn size(&self) -> usize {
-        self.size
+    /// Store a value for a key in the cache's memory, replacing any value already stored for it,
+    /// and return whether it was stored. A value too heavy to ever fit in the cache, or with no
+    /// room left beside pinned values, is not stored, but still replaces the old value. Pinned
+    /// values are always stored.
+    fn store<Q>(&mut self, key: &Q, v: P, expires: Option<Instant>) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let weight = match &self.weigher {
+            Some(weigher) => {
+                let (k, _) = self
+                    .map
+                    .get_key_value(key)
+                    .expect("Only keys in the cache's memory can have values stored");
+                weigher(k, &v)
+            }
+            None => 1,
+        };
+        if let Some(weak) = &mut self.weak {
+            weak.remove(key);
+        }
+        let entry = self
+            .map
+            .get_mut(key)
+            .expect("Only keys in the cache's memory can have values stored");
+        let fits = entry.pinned || (weight <= self.max_weight && self.max_entries > 0);
+        let old = if fits {
+            entry.expires = if entry.pinned { None } else { expires };
+            entry.value.replace(v)
+        } else {
+            entry.value.take()
+        };
+        let old_weight = mem::replace(&mut entry.weight, weight);
+        if let Some(old) = old {
+            self.size -= 1;
+            self.weight -= old_weight;
+            let map = &self.map;
+            self.evictions.report(
+                || map.get_key_value(key).unwrap().0.clone(),
+                old,
+                EvictionReason::Replaced,
+            );
+        }
+        if !fits {
+            return false;
+        }
+        self.size += 1;
+        self.weight += weight;
+        self.evict_over_capacity(Some(key));
+        if self.weight > self.max_weight || self.size > self.max_entries {
+            // Only pinned values are left, so there's no room for this one after all
+            let entry = self.map.get_mut(key).unwrap();
+            if !entry.pinned {
+                entry.value = None;
+                self.size -= 1;
+                self.weight -= weight;
+                return false;
+            }
+        }
+        true
     }
 
-    /// Get the length of the cache's recent request memory.
-    pub fn mem_len(&self) -> usize {
-        self.mem_len
+    /// Store a value that stays in the cache regardless of how it's requested, replacing any value
+    /// already stored for the key, and return it.
+    ///
+    /// A pinned value skips admission, so it's stored even if its key was never requested, and
+    /// never expires. It isn't forgotten when its requests leave the memory, whether from new
+    /// requests or a [shorter memory](Self::set_mem_len), and it isn't dropped to stay within the
+    /// cache's limits. It still counts towards them, though, so other values are dropped in its
+    /// place, or not stored at all if pinned values take up all the room. Requests for it are
+    /// remembered and counted as usual.
+    ///
+    /// A pinned value only leaves the cache once it's [unpinned](Self::unpin),
+    /// [popped](Self::pop), or cleared out by [`clear_cache`](Self::clear_cache).
+    pub fn pin(&mut self, key: K, v: V) -> P {
+        if !self.map.contains_key(&key) {
+            let entry = Record {
+                counter: 0,
+                recent: 0,
+                value: None,
+                weight: 0,
+                expires: None,
+                pinned: false,
+            };
+            self.map.insert(key.clone(), entry);
+            self.idle_pins += 1;
+        }
+        self.map.get_mut(&key).unwrap().pinned = true;
+        let v = P::new(v);
+        self.store(&key, v.clone(), None);
+        v
     }
 
-    /// Change the length of the cache's recent request memory. Some contents of the cache may be
-    /// removed immediately if the new memory length is shorter than the old memory length.
-    pub fn set_mem_len(&mut self, new_len: usize) {
-        // Just make it work if an invalid value is thrown in
-        let new_len = new_len.clamp(2, u32::MAX as usize);
-        // Remove any excess memory
-        while self.list.len() > new_len {
-            let (key, last_count) = self
-                .list
-                .pop_back()
-                .expect("Cache memory queue should be non-empty at this point");
-            let (counter, val) = self
-                .map
-                .get(&key)
-                .expect("Cache hashmap should contain the key from the memory queue");
-            if *counter == last_count {
-                if val.is_some() {
-                    self.size -= 1;
+    /// Unpin a key's value, returning whether it was [pinned](Self::pin). The value stays stored
+    /// as long as its key is in the cache's recent request memory, and from then on can be
+    /// forgotten or dropped like any other value. If there are no requests for the key in memory,
+    /// it's forgotten right away.
+    ///
+    /// The key may be any borrowed form of the cache's key type, but [`Hash`] and [`Eq`] on the
+    /// borrowed form *must* match those for the key type.
+    pub fn unpin<Q>(&mut self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let Some(entry) = self.map.get_mut(key) else {
+            return false;
+        };
+        if !mem::take(&mut entry.pinned) {
+            return false;
+        }
+        if entry.recent == 0 {
+            self.idle_pins -= 1;
+            let (key, entry) = self.map.remove_entry(key).unwrap();
+            if let Some(v) = entry.value {
+                self.size -= 1;
+                self.weight -= entry.weight;
+                if let Some(weak) = &mut self.weak {
+                    weak.insert(key.clone(), &v);
                 }
-                self.map.remove(&key);
+                self.evictions.report(|| key, v, EvictionReason::Forgotten);
             }
+        } else {
+            self.evict_over_capacity::<K>(None);
         }
-        self.mem_len = new_len;
+        true
     }
 
-    /// Clear out all stored values and all memory in the cache.
-    pub fn clear_cache(&mut self) {
-        self.size = 0;
-        self.map.clear();
-        self.list.clear();
+    /// Check if a key's value is [pinned](Self::pin). This does not update the cache's memory.
+    ///
+    /// The key may be any borrowed form of the cache's key type, but [`Hash`] and [`Eq`] on the
+    /// borrowed form *must* match those for the key type.
+    pub fn is_pinned<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.map.get(key).is_some_and(|entry| entry.pinned)
     }
 
-    /// Get the number of hits this cache has seen.
-    pub fn hits(&self) -> u64 {
-        self.hits
+    /// Get the given key's entry in the cache for in-place manipulation, recording a request for
+    /// it exactly like [`get`](Self::get) does. The entry says whether a value was stored, or if
+    /// not, whether the key had already been requested recently.
+    ///
+    /// The key is only looked up once to get the entry, and inserting a value through a
+    /// [`TrackedEntry`] or [`VacantEntry`] reuses what that lookup found, instead of going through
+    /// [`insert`](Self::insert)'s checks again.
+    pub fn entry(&mut self, key: K) -> Entry<'_, K, V, S, P> {
+        let (value, recent, admit) = self.request(&key);
+        match value {
+            Some(value) => Entry::Occupied(OccupiedEntry {
+                cache: self,
+                key,
+                value,
+            }),
+            None if recent > 1 => Entry::Tracked(TrackedEntry {
+                cache: self,
+                key,
+                recent,
+                admit,
+            }),
+            None => Entry::Vacant(VacantEntry {
+                cache: self,
+                key,
+                admit,
+            }),
+        }
     }
 
-    /// Get the number of misses this cache has seen.
-    pub fn misses(&self) -> u64 {
-        self.misses
+    /// Fetch an item via the cache, potentially filling in the cache on a miss via the function
+    /// `f`.
+    ///
+    /// The key may be any borrowed form of the cache's key type, but [`Hash`] and [`Eq`] on the
+    /// borrowed form *must* match those for the key type.
+    pub fn get_or_insert<Q, F>(&mut self, key: &Q, f: F) -> P
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq + ToOwned<Owned = K>,
+        F: FnOnce() -> V,
+    {
+        self.get(key).unwrap_or_else(|| self.insert(key, f()))
     }
 
-    /// Reset the cache hit/miss metrics.
-    pub fn reset_metrics(&mut self) {
-        self.hits = 0;
-        self.misses = 0;
+    /// Fetch an item via the cache, potentially filling in the cache on a miss via the fallible
+    /// function `f`. If `f` fails, the error is returned and nothing is stored. The request is
+    /// still remembered and counted as a miss, just as it would be by [`get`](Self::get).
+    ///
+    /// The key may be any borrowed form of the cache's key type, but [`Hash`] and [`Eq`] on the
+    /// borrowed form *must* match those for the key type.
+    pub fn get_or_try_insert<Q, E, F>(&mut self, key: &Q, f: F) -> Result<P, E>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq + ToOwned<Owned = K>,
+        F: FnOnce() -> Result<V, E>,
+    {
+        match self.get(key) {
+            Some(v) => Ok(v),
+            None => Ok(self.insert(key, f()?)),
+        }
     }
-}
 
-impl<K: fmt::Debug, V: fmt::Debug, S> fmt::Debug for DynamicCacheLocal<K, V, S> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_struct("DynamicCacheLocal")
-            .field("map", &format!("{} entries", self.map.len()))
-            .field("list", &format!("{} long", self.list.len()))
-            .field("mem_len", &self.mem_len)
-            .field("size", &self.size)
-            .finish()
+    /// Retrieve a value from the cache without updating the cache's memory of what values have
+    /// been requested, or its hit/miss metrics.
+    ///
+    /// The key may be any borrowed form of the cache's key type, but [`Hash`] and [`Eq`] on the
+    /// borrowed form *must* match those for the key type.
+    pub fn peek<Q>(&self, key: &Q) -> Option<P>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.map
+            .get(key)
+            .filter(|entry| !entry.expired(&*self.clock))
+            .and_then(|entry| entry.value.clone())
     }
-}
 
-/// A cache that will only hold onto items that have been reqeuested more than once in recent
-/// memory. Single-use items are not held at all. Once an item is requested twice, it is cached
-/// until all memory of seeing it requested has expired. The length of the memory is adjustable,
-/// and must be set at initialization.
-///
-/// This version of the cache can be shared across threads without issue, as it is an instance of
-/// [`DynamicCacheLocal`] held by an `Arc<Mutex<T>>`.
-#[derive(Clone, Debug)]
-pub struct DynamicCache<K, V, S = RandomState> {
-    cache: Arc<Mutex<DynamicCacheLocal<K, V, S>>>,
-}
+    /// Get the stored value for a key along with the key as held by the cache, without updating
+    /// the cache's memory.
+    fn stored<Q>(&self, key: &Q) -> Option<(&K, &P)>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let (k, entry) = self.map.get_key_value(key)?;
+        match &entry.value {
+            Some(v) if !entry.expired(&*self.clock) => Some((k, v)),
+            _ => None,
+        }
+    }
 
-impl<K: Clone + Eq + Hash, V, S> DynamicCache<K, V, S> {
-    /// Create and initialize a new cache, using the given hash builder to hash keys. The same
-    /// warnings given for [`HashMap::with_hasher`] apply here.
-    pub fn with_hasher(mem_len: usize, hash_builder: S) -> DynamicCache<K, V, S> {
-        Self {
-            cache: Arc::new(Mutex::new(DynamicCacheLocal::with_hasher(
-                mem_len,
-                hash_builder,
-            ))),
+    /// Check if a value is currently stored in the cache. This does not update the cache's memory
+    /// of what values have been requested.
+    ///
+    /// The key may be any borrowed form of the cache's key type, but [`Hash`] and [`Eq`] on the
+    /// borrowed form *must* match those for the key type.
+    pub fn contains<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.peek(key).is_some()
+    }
+
+    /// Check if there are any requests for a key in the cache's recent request memory, whether or
+    /// not a value is stored for it. This does not update the cache's memory.
+    ///
+    /// With a [memory duration](Self::set_mem_duration), requests older than the duration are only
+    /// forgotten when the next request is made, so they may still be counted here. With a
+    /// [sketch](Self::with_sketch), this checks the sketch's approximate count, which includes
+    /// requests for other keys that collide with this one, so it can be true for a key that was
+    /// never requested.
+    ///
+    /// The key may be any borrowed form of the cache's key type, but [`Hash`] and [`Eq`] on the
+    /// borrowed form *must* match those for the key type.
+    pub fn is_tracked<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        if self.sketch.is_some() {
+            return self.recent_request_count(key) > 0;
         }
+        self.map.get(key).is_some_and(|entry| entry.recent > 0)
     }
-}
 
-impl<K: Clone + Eq + Hash, V> DynamicCache<K, V> {
-    /// Create an initialize a new cache.
-    pub fn new(mem_len: usize) -> Self {
-        Self {
-            cache: Arc::new(Mutex::new(DynamicCacheLocal::new(mem_len))),
+    /// Get the number of requests for a key in the cache's recent request memory. This does not
+    /// update the cache's memory.
+    ///
+    /// With a [memory duration](Self::set_mem_duration), requests older than the duration are only
+    /// forgotten when the next request is made, so they may still be counted here. With a
+    /// [sketch](Self::with_sketch), this is the sketch's approximate count, which includes
+    /// requests for other keys that collide with this one, so it can be nonzero for a key that was
+    /// never requested.
+    ///
+    /// The key may be any borrowed form of the cache's key type, but [`Hash`] and [`Eq`] on the
+    /// borrowed form *must* match those for the key type.
+    pub fn recent_request_count<Q>(&self, key: &Q) -> u32
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        match &self.sketch {
+            Some(sketch) => sketch.estimate(self.map.hasher().hash_one(key)),
+            None => self.map.get(key).map_or(0, |entry| entry.recent),
         }
     }
 
-    /// Attempt to retrieve a value from the cache. This updates the cache's memory of what values
-    /// have been requested.
-    pub fn get(&self, key: &K) -> Option<Arc<V>> {
-        self.cache.lock().unwrap().get(key)
+    /// Get the number of items currently stored in the cache.
+    pub fn size(&self) -> usize {
+        self.size
     }
 
-    /// Attempt to remove a value from the cache.
-    ///
-    /// This will remove the value itself from the cache, but doesn't change the cache's stored 
-    /// request history. This means that any new [`get_or_insert`] calls will re-load the value 
-    /// into the cache.
-    pub fn pop(&self, key: &K) -> Option<Arc<V>> {
-        self.cache.lock().unwrap().pop(key)
+    /// Get the total weight of all items currently stored in the cache. Without a
+    /// [weigher](Self::set_weigher), every item weighs 1 and this is the same as
+    /// [`size`](Self::size).
+    pub fn weight(&self) -> usize {
+        self.weight
     }
 
-    /// Insert a value into the cache. If the value is already present, an `Arc<V>` of the stored
-    /// value is returned. If the value is not stored but has been requested more than once, then
-    /// it is stored and returned. If the value is not stored and hasn't been requested more than
-    /// once, it is not stored and is simply returned, wrapped as an `Arc<V>`.
-    pub fn insert(&self, key: &K, value: V) -> Arc<V> {
-        self.cache.lock().unwrap().insert(key, value)
+    /// Get the maximum total weight of stored items. This is `usize::MAX`, meaning unlimited,
+    /// unless changed with [`set_max_weight`](Self::set_max_weight).
+    pub fn max_weight(&self) -> usize {
+        self.max_weight
     }
 
-    /// Fetch an item via the cache, potentially filling in the cache on a miss via the function
-    /// `f`. The cache is unlocked while calling the function, so `f` may be called more than once
-    /// with the same parameters if there are several threads using the cache.
-    pub fn get_or_insert<F: FnOnce() -> V>(&self, key: &K, f: F) -> Arc<V> {
-        self.get(key).unwrap_or_else(|| self.insert(key, f()))
+    /// Change the maximum total weight of stored items. If the cache is currently heavier than
+    /// this, the least recently requested items are removed immediately until it fits.
+    pub fn set_max_weight(&mut self, max_weight: usize) {
+        self.max_weight = max_weight;
+        self.evict_over_capacity::<K>(None);
     }
 
-    /// Get the number of items currently stored in the cache.
-    pub fn size(&self) -> usize {
-        self.cache.lock().unwrap().size()
+    /// Set the function used to weigh stored items, replacing the default weight of 1 per item.
+    /// The weights of items already in the cache are recalculated, and items are removed if the
+    /// cache is now over its maximum weight.
+    pub fn set_weigher<F>(&mut self, weigher: F)
+    where
+        F: Fn(&K, &V) -> usize + Send + Sync + 'static,
+    {
+        self.weight = 0;
+        for (k, entry) in self.map.iter_mut() {
+            if let Some(v) = &entry.value {
+                entry.weight = weigher(k, v);
+                self.weight += entry.weight;
+            }
+        }
+        self.weigher = Some(Box::new(weigher));
+        self.evict_over_capacity::<K>(None);
     }
 
-    /// Get the length of the cache's recent request memory.
-    pub fn mem_len(&self) -> usize {
-        self.cache.lock().unwrap().mem_len()
+    /// Get the maximum number of items that can be stored in the cache at once. This is
+    /// `usize::MAX`, meaning only limited by the memory length, unless changed with
+    /// [`set_max_entries`](Self::set_max_entries).
+    pub fn max_entries(&self) -> usize {
+        self.max_entries
     }
 
-    /// Change the length of the cache's recent request memory. Some contents of the cache may be
-    /// removed immediately if the new memory length is shorter than the old memory length.
-    pub fn set_mem_len(&self, new_len: usize) {
-        self.cache.lock().unwrap().set_mem_len(new_len)
+    /// Change the maximum number of items that can be stored in the cache at once. If more items
+    /// than this are currently stored, the least recently requested items are removed
+    /// immediately.
+    pub fn set_max_entries(&mut self, max_entries: usize) {
+        self.max_entries = max_entries;
+        self.evict_over_capacity::<K>(None);
     }
 
-    /// Clear out all stored values and all memory in the cache.
-    pub fn clear_cache(&self) {
-        self.cache.lock().unwrap().clear_cache()
+    /// Get the default time-to-live for newly stored values. This is `None`, meaning values don't
+    /// expire, unless changed with [`set_ttl`](Self::set_ttl).
+    pub fn ttl(&self) -> Option<Duration> {
+        self.ttl
     }
 
-    /// Get the cache metrics as a pair `(hits, misses)`.
-    pub fn hits_misses(&self) -> (u64, u64) {
-        let cache = self.cache.lock().unwrap();
-        (cache.hits(), cache.misses())
+    /// Change the default time-to-live for newly stored values. Values already in the cache keep
+    /// their current expiration time.
+    ///
+    /// Expired values are treated as missing, and are dropped the next time they're requested or
+    /// inserted again. The cache's memory of requests for an expired value is kept, so the reloaded
+    /// value is usually stored again right away.
+    pub fn set_ttl(&mut self, ttl: Option<Duration>) {
+        self.ttl = ttl;
     }
 
-    /// Reset the cache hit/miss metrics.
-    pub fn reset_metrics(&self) {
-        self.cache.lock().unwrap().reset_metrics()
+    /// Replace the clock used for time-to-live expiration and time-limited request memory, which is
+    /// [`SystemClock`] by default.
+    pub fn set_clock<C: Clock + 'static>(&mut self, clock: C) {
+        self.clock = Box::new(clock);
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    use rand::prelude::*;
+    /// Get the length of the cache's recent request memory.
+    pub fn mem_len(&self) -> usize {
+        self.mem_len
+    }
 
-    #[test]
-    fn fetch_test() {
-        let (key, val) = (0, String::from("0"));
-        let cache = DynamicCache::new(8);
-        assert_eq!(cache.size(), 0);
-        assert_eq!(cache.mem_len(), 8);
-        assert_eq!(cache.hits_misses(), (0, 0));
+    /// Get the number of recent requests a key needs before its value is stored in the cache.
+    pub fn admit_threshold(&self) -> u32 {
+        self.admit_threshold
+    }
 
-        assert!(
-            cache.get(&key).is_none(),
-            "First `get` should have nothing in cache"
-        );
-        assert_eq!(cache.size(), 0);
-        assert_eq!(cache.hits_misses(), (0, 1));
+    /// Change the number of recent requests a key needs before its value is stored in the cache.
+    /// A threshold of 1 stores values on their first request. Values that are already stored are
+    /// not affected.
+    ///
+    /// With the default threshold of 2, a value is stored on any second request for its key
+    /// before all of the key's requests are forgotten, even if the first request has already left
+    /// the memory. With any other threshold, only requests still in the recent request memory
+    /// count, so a key has to be requested that many times within one memory length (or [memory
+    /// duration](Self::set_mem_duration)).
+    pub fn set_admit_threshold(&mut self, threshold: u32) {
+        // Just make it work if an invalid value is thrown in
+        self.admit_threshold = threshold.max(1);
+    }
+
+    /// Change the length of the cache's recent request memory. Some contents of the cache may be
+    /// removed immediately if the new memory length is shorter than the old memory length.
+    pub fn set_mem_len(&mut self, new_len: usize) {
+        // Just make it work if an invalid value is thrown in
+        let new_len = new_len.clamp(2, u32::MAX as usize);
+        // Remove any excess memory
+        while self.list.len() > new_len {
+            self.forget_oldest(EvictionReason::Resized);
+        }
+        self.mem_len = new_len;
+        self.forget_distant(EvictionReason::Resized);
+    }
+
+    /// Get the bounds the memory length is kept within, if it's [tuned
+    /// automatically](Self::set_auto_mem_len).
+    pub fn auto_mem_len(&self) -> Option<RangeInclusive<usize>> {
+        self.tuner.as_ref().map(|tuner| tuner.bounds.clone())
+    }
+
+    /// Tune the memory length automatically, keeping it within the given bounds, or stop tuning
+    /// it with `None`. The memory length is moved into the bounds immediately.
+    ///
+    /// Requests are looked at in periods of one memory length each. At the end of each period,
+    /// the cache checks how many of its misses were for keys it had forgotten recently, which a
+    /// longer memory would have kept. If there were many of these, and the cache isn't already
+    /// at its [maximum entry count](Self::max_entries) or [weight](Self::max_weight), the memory
+    /// grows by a quarter. If there were hardly any, it shrinks by an eighth. A shrink that lowers
+    /// the hit ratio or brings on forgotten keys is undone at the end of the next period, and the
+    /// memory isn't shrunk that far again until the cache is [cleared](Self::clear_cache) or the
+    /// bounds are set again.
+    ///
+    /// Recently forgotten keys are remembered by their hash alone, taking about 16 bytes for
+    /// each request the memory could still grow by.
+    ///
+    /// Each change is made with [`set_mem_len`](Self::set_mem_len), so values forgotten by a
+    /// shrink are reported as [`EvictionReason::Resized`]. Setting the memory length directly
+    /// still works, but it may be changed again at the end of the current period.
+    pub fn set_auto_mem_len(&mut self, bounds: Option<RangeInclusive<usize>>) {
+        self.tuner = bounds.map(|bounds| {
+            // Just make it work if an invalid value is thrown in
+            let min = (*bounds.start()).clamp(2, u32::MAX as usize);
+            let max = (*bounds.end()).clamp(min, u32::MAX as usize);
+            Box::new(MemTuner::new(min..=max))
+        });
+        if let Some(bounds) = self.auto_mem_len() {
+            self.set_mem_len(self.mem_len.clamp(*bounds.start(), *bounds.end()));
+        }
+    }
+
+    /// Count a request towards automatic tuning of the memory length, resizing the memory if
+    /// that's due.
+    fn tune(&mut self, hit: bool) {
+        let Some(tuner) = &mut self.tuner else {
+            return;
+        };
+        let full = self.size >= self.max_entries || self.weight >= self.max_weight;
+        if let Some(new_len) = tuner.request(hit, self.mem_len, full) {
+            self.set_mem_len(new_len);
+        }
+    }
+
+    /// Check whether values that leave the cache are kept as weak references. See
+    /// [`set_weak_values`](Self::set_weak_values).
+    pub fn weak_values(&self) -> bool {
+        self.weak.is_some()
+    }
+
+    /// Choose whether values that leave the cache are kept as weak references. This is off by
+    /// default, and turning it off forgets all weak references.
+    ///
+    /// When on, a value forgotten along with its request history, or dropped to stay within the
+    /// cache's limits, is downgraded to a [`Weak`](std::sync::Weak) reference instead of being
+    /// let go entirely. If something else still holds the value when its key is next requested,
+    /// [`get`](Self::get) returns it and stores it in the cache again, instead of missing and
+    /// having it loaded a second time. These requests count as hits, and are also counted as
+    /// [resurrections](CacheStats::resurrections).
+    ///
+    /// Values that expired, were popped, replaced or cleared out aren't kept. Neither are values
+    /// that nothing else holds, so the weak references only cost memory for values still in use.
+    ///
+    /// The weak references are looked up with a clone of the cache's hash builder.
+    pub fn set_weak_values(&mut self, enabled: bool)
+    where
+        S: Clone,
+    {
+        self.weak = enabled.then(|| {
+            self.weak
+                .take()
+                .unwrap_or_else(|| WeakValues::with_hasher(self.map.hasher().clone()))
+        });
+    }
+
+    /// Get how long requests are kept in the cache's recent request memory. This is `None`,
+    /// meaning requests are only forgotten once the memory is full, unless changed with
+    /// [`set_mem_duration`](Self::set_mem_duration).
+    pub fn mem_duration(&self) -> Option<Duration> {
+        self.mem_duration
+    }
+
+    /// Change how long requests are kept in the cache's recent request memory. Requests older than
+    /// this are forgotten even if the memory isn't full, along with keys that have no newer
+    /// requests. The memory length still applies as well; set it
+    /// very high with [`set_mem_len`](Self::set_mem_len) to only forget requests based on time.
+    ///
+    /// Requests made before a duration was set are only ever forgotten once the memory is full.
+    pub fn set_mem_duration(&mut self, duration: Option<Duration>) {
+        self.mem_duration = duration;
+        if duration.is_some() {
+            self.forget_stale(Some(self.clock.now()), EvictionReason::Resized);
+        }
+    }
+
+    /// Remove the least recently requested stored values until the cache is within its capacity
+    /// limits. The value for `keep`, if given, is never removed.
+    fn evict_over_capacity<Q>(&mut self, keep: Option<&Q>)
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        // Walking the memory from oldest to newest, the first request found that is also the
+        // latest request for its key belongs to the least recently requested key.
+        for (key, count, _) in self.list.iter().rev() {
+            if self.weight <= self.max_weight && self.size <= self.max_entries {
+                break;
+            }
+            if keep.is_some_and(|keep| key.borrow() == keep) {
+                continue;
+            }
+            let entry = self
+                .map
+                .get_mut::<K>(key)
+                .expect("Cache hashmap should contain the key from the memory queue");
+            if entry.counter != *count || entry.pinned {
+                continue;
+            }
+            if let Some(v) = entry.value.take() {
+                self.size -= 1;
+                self.weight -= entry.weight;
+                if let Some(weak) = &mut self.weak {
+                    weak.insert(key.clone(), &v);
+                }
+                self.evictions
+                    .report(|| key.clone(), v, EvictionReason::Capacity);
+            }
+        }
+    }
+
+    /// Forget all requests that are older than the memory duration, as of `now`.
+    fn forget_stale(&mut self, now: Option<Instant>, reason: EvictionReason) {
+        while self.oldest_is_stale(now) {
+            self.forget_oldest(reason);
+        }
+    }
+
+    /// Check if the oldest request in memory is older than the memory duration, as of `now`.
+    fn oldest_is_stale(&self, now: Option<Instant>) -> bool {
+        let (Some(now), Some(duration)) = (now, self.mem_duration) else {
+            return false;
+        };
+        match self.list.back() {
+            Some((_, _, Some(at))) => now.saturating_duration_since(*at) >= duration,
+            _ => false,
+        }
+    }
+
+    /// Forget all requests made more than a memory length of requests ago. This only applies to
+    /// caches with a sketch, as their memory doesn't hold every request.
+    fn forget_distant(&mut self, reason: EvictionReason) {
+        while self.oldest_is_distant() {
+            self.forget_oldest(reason);
+        }
+    }
+
+    /// Check if the oldest request in memory was made more than a memory length of requests ago.
+    fn oldest_is_distant(&self) -> bool {
+        let mem_len = self.mem_len as u64;
+        self.sketch.as_ref().is_some_and(|sketch| {
+            sketch
+                .positions
+                .back()
+                .is_some_and(|&at| at + mem_len <= sketch.requests)
+        })
+    }
+
+    /// Drop the oldest request from the cache's memory, removing the key entirely if that was the
+    /// last request for it still remembered. A stored value removed this way is reported with the
+    /// given reason.
+    fn forget_oldest(&mut self, reason: EvictionReason) {
+        let (key, last_count, _) = self
+            .list
+            .pop_back()
+            .expect("Cache memory queue should be non-empty at this point");
+        if let Some(sketch) = &mut self.sketch {
+            sketch.positions.pop_back();
+        }
+        let entry = self
+            .map
+            .get_mut(&key)
+            .expect("Cache hashmap should contain the key from the memory queue");
+        entry.recent -= 1;
+        if entry.counter == last_count {
+            if entry.pinned {
+                // Pinned values stay stored with no requests in memory
+                self.idle_pins += 1;
+                return;
+            }
+            let entry = self.map.remove(&key).unwrap();
+            if let Some(tuner) = &mut self.tuner {
+                tuner.forgot(self.map.hasher().hash_one(&key), self.mem_len);
+            }
+            if let Some(v) = entry.value {
+                self.size -= 1;
+                self.weight -= entry.weight;
+                if let Some(weak) = &mut self.weak {
+                    weak.insert(key.clone(), &v);
+                }
+                self.evictions.report(|| key, v, reason);
+            }
+        }
+    }
+
+    /// Iterate over all values stored in the cache, in arbitrary order. This does not update the
+    /// cache's memory of what values have been requested.
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &P)> {
+        let clock = &*self.clock;
+        self.map
+            .iter()
+            .filter_map(move |(k, entry)| match &entry.value {
+                Some(v) if !entry.expired(clock) => Some((k, v)),
+                _ => None,
+            })
+    }
+
+    /// Iterate over the keys of all values stored in the cache, in arbitrary order.
+    pub fn keys(&self) -> impl Iterator<Item = &K> {
+        self.iter().map(|(k, _)| k)
+    }
+
+    /// Iterate over the keys in the cache's recent request memory, one for each remembered
+    /// request, from the most recent request to the oldest.
+    pub fn history(&self) -> impl Iterator<Item = &K> {
+        self.list.iter().map(|(k, _, _)| k)
+    }
+
+    /// Get every key in the cache's recent request memory along with its [recent request
+    /// count](Self::recent_request_count), ordered from the least to the most recently requested.
+    ///
+    /// This is the part of the cache's state that decides what gets stored, so it can be saved
+    /// and given to [`import_history`](Self::import_history) after a restart, even if the values
+    /// themselves can't be saved.
+    pub fn export_history(&self) -> Vec<(K, u32)> {
+        self.list
+            .iter()
+            .rev()
+            .filter(|(key, counter, _)| self.map[key].counter == *counter)
+            .map(|(key, _, _)| (key.clone(), self.recent_request_count::<K>(key)))
+            .collect()
+    }
+
+    /// Add requests to the cache's memory, as exported by
+    /// [`export_history`](Self::export_history): each key is requested as many times as its
+    /// count, in order. Previously hot keys then have their values stored on the next
+    /// [`insert`](Self::insert), rather than needing to be requested again first.
+    ///
+    /// The requests are made as usual, pushing older requests out of the memory, but they aren't
+    /// counted as hits or misses. No more requests than fit in the memory are made for any key.
+    pub fn import_history<I>(&mut self, history: I)
+    where
+        I: IntoIterator<Item = (K, u32)>,
+    {
+        for (key, count) in history {
+            for _ in 0..(count as usize).min(self.mem_len) {
+                self.remember(key.clone());
+            }
+        }
+    }
+
+    /// Add a request for a key to the cache's memory, without looking at or counting the value
+    /// stored for it.
+    fn remember(&mut self, key: K) {
+        if let Some(sketch) = &mut self.sketch {
+            sketch.record(self.map.hasher().hash_one(&key));
+            sketch.requests += 1;
+        }
+        let counter = match self.map.get_mut(&key) {
+            Some(entry) => {
+                if entry.recent == 0 {
+                    self.idle_pins -= 1;
+                }
+                entry.counter = entry.counter.wrapping_add(1);
+                entry.recent += 1;
+                entry.counter
+            }
+            None => {
+                let entry = Record {
+                    counter: 0,
+                    recent: 1,
+                    value: None,
+                    weight: 0,
+                    expires: None,
+                    pinned: false,
+                };
+                self.map.insert(key.clone(), entry);
+                0
+            }
+        };
+        self.push_request(key, counter);
+    }
+
+    /// Remember a request for a key that was already served as a hit without going through the
+    /// cache, and count the hit. Nothing is tuned or resurrected, as the request is already over.
+    /// If the key's value has left the cache since, only the hit is counted, so the request can't
+    /// bring the key back into the cache's memory.
+    pub(crate) fn replay_hit(&mut self, key: K) {
+        self.hits += 1;
+        if self.stored(&key).is_some() {
+            self.remember(key);
+        }
+    }
+
+    /// Clear out all memory in the cache, returning all values that were stored in it. If the
+    /// returned iterator is dropped before being fully consumed, the remaining values are dropped
+    /// too.
+    ///
+    /// The drained values are handed to the caller, so they aren't reported to the eviction
+    /// listener.
+    pub fn drain(&mut self) -> impl Iterator<Item = (K, P)> + '_ {
+        self.idle_pins = 0;
+        self.size = 0;
+        self.weight = 0;
+        self.list.clear();
+        if let Some(sketch) = &mut self.sketch {
+            sketch.clear();
+        }
+        if let Some(tuner) = &mut self.tuner {
+            tuner.clear();
+        }
+        if let Some(weak) = &mut self.weak {
+            weak.clear();
+        }
+        let clock = &*self.clock;
+        self.map
+            .drain()
+            .filter_map(move |(k, entry)| match entry.value {
+                Some(v) if !entry.expired(clock) => Some((k, v)),
+                _ => None,
+            })
+    }
+
+    /// Clear out all stored values and all memory in the cache, including [pinned](Self::pin)
+    /// values. Use [`clear_unpinned`](Self::clear_unpinned) to keep them.
+    pub fn clear_cache(&mut self) {
+        self.idle_pins = 0;
+        self.size = 0;
+        self.weight = 0;
+        self.list.clear();
+        if let Some(sketch) = &mut self.sketch {
+            sketch.clear();
+        }
+        if let Some(tuner) = &mut self.tuner {
+            tuner.clear();
+        }
+        if let Some(weak) = &mut self.weak {
+            weak.clear();
+        }
+        for (k, entry) in self.map.drain() {
+            if let Some(v) = entry.value {
+                self.evictions.report(|| k, v, EvictionReason::Cleared);
+            }
+        }
+    }
+
+    /// Clear out all memory in the cache and all stored values, except for [pinned](Self::pin)
+    /// values, which stay stored.
+    pub fn clear_unpinned(&mut self) {
+        self.list.clear();
+        if let Some(sketch) = &mut self.sketch {
+            sketch.clear();
+        }
+        if let Some(tuner) = &mut self.tuner {
+            tuner.clear();
+        }
+        if let Some(weak) = &mut self.weak {
+            weak.clear();
+        }
+        let evictions = &mut self.evictions;
+        self.map.retain(|k, entry| {
+            if entry.pinned {
+                entry.recent = 0;
+                return true;
+            }
+            if let Some(v) = entry.value.take() {
+                evictions.report(|| k.clone(), v, EvictionReason::Cleared);
+            }
+            false
+        });
+        self.idle_pins = self.map.len();
+        self.size = self.map.len();
+        self.weight = self.map.values().map(|entry| entry.weight).sum();
+    }
+
+    /// Set a function to be called with every stored value that leaves the cache, along with its
+    /// key and the reason it was removed. This covers values forgotten along with their request
+    /// history, expired, popped, replaced, dropped for capacity, or cleared out, but not values
+    /// returned by [`drain`](Self::drain).
+    ///
+    /// The listener is called while the cache is being modified, so it can't use the cache
+    /// itself.
+    pub fn set_eviction_listener<F>(&mut self, listener: F)
+    where
+        F: Fn(K, P, EvictionReason) + Send + Sync + 'static,
+    {
+        self.evictions.set_listener(Arc::new(listener));
+    }
+
+    /// Get the number of hits this cache has seen.
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    /// Get the number of misses this cache has seen.
+    pub fn misses(&self) -> u64 {
+        self.misses
+    }
+
+    /// Get a snapshot of the cache's statistics. This includes the hit and miss counts, along with
+    /// counts of admissions, rejected inserts, pops and evictions, and the current size of the
+    /// cache's memory.
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits,
+            misses: self.misses,
+            admissions: self.admissions,
+            rejections: self.rejections,
+            pops: self.pops,
+            resurrections: self.resurrections,
+            evictions: self.evictions.counts,
+            history_len: self.list.len(),
+            tracked: self.map.len() - self.idle_pins,
+            stored: self.size,
+        }
+    }
+
+    /// Reset all of the cache's metrics, including everything counted by [`stats`](Self::stats).
+    pub fn reset_metrics(&mut self) {
+        self.hits = 0;
+        self.misses = 0;
+        self.admissions = 0;
+        self.rejections = 0;
+        self.pops = 0;
+        self.resurrections = 0;
+        self.evictions.counts = EvictionCounts::default();
+    }
+}
+
+impl<K: Clone + Eq + Hash, V, S: BuildHasher + Default> Default for DynamicCacheLocal<K, V, S> {
+    /// Create an empty cache with a memory length of [`DEFAULT_MEM_LEN`].
+    fn default() -> Self {
+        Self::with_hasher(DEFAULT_MEM_LEN, S::default())
+    }
+}
+
+impl<K: fmt::Debug, V: fmt::Debug, S, P: SharedPtr<V>> fmt::Debug
+    for DynamicCacheLocal<K, V, S, P>
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DynamicCacheLocal")
+            .field("map", &format!("{} entries", self.map.len()))
+            .field("list", &format!("{} long", self.list.len()))
+            .field("mem_len", &self.mem_len)
+            .field("mem_duration", &self.mem_duration)
+            .field("admit_threshold", &self.admit_threshold)
+            .field("max_weight", &self.max_weight)
+            .field("weight", &self.weight)
+            .field("max_entries", &self.max_entries)
+            .field("ttl", &self.ttl)
+            .field("sketch", &self.sketch.is_some())
+            .field(
+                "auto_mem_len",
+                &self.tuner.as_ref().map(|tuner| &tuner.bounds),
+            )
+            .field("weak_values", &self.weak.is_some())
+            .field("size", &self.size)
+            .finish()
+    }
+}
+
+/// A cache that will only hold onto items that have been reqeuested more than once in recent
+/// memory. Single-use items are not held at all. Once an item is requested twice, it is cached
+/// until all memory of seeing it requested has expired. The length of the memory is adjustable,
+/// and must be set at initialization.
+///
+/// This version of the cache can be shared across threads without issue, as it is an instance of
+/// [`DynamicCacheLocal`] held by an `Arc<Mutex<T>>`. When many threads use the cache at once,
+/// [`ShardedDynamicCache`] splits it up so they don't all wait on the same lock, and
+/// [`BufferedDynamicCache`] serves hits without taking the lock at all.
+#[derive(Clone, Debug)]
+pub struct DynamicCache<K, V, S = RandomState> {
+    cache: Arc<Mutex<DynamicCacheLocal<K, V, S>>>,
+    loading: Arc<InFlight<K, V>>,
+}
+
+impl<K: Clone + Eq + Hash, V> DynamicCache<K, V> {
+    /// Create an initialize a new cache.
+    pub fn new(mem_len: usize) -> Self {
+        Self::with_hasher(mem_len, RandomState::new())
+    }
+
+    /// Create and initialize a new cache, with space for at least `capacity` tracked keys
+    /// allocated up front.
+    pub fn with_capacity(mem_len: usize, capacity: usize) -> Self {
+        Self::with_capacity_and_hasher(mem_len, capacity, RandomState::new())
+    }
+
+    /// Create and initialize a new cache that counts requests with a compact sketch, instead of
+    /// remembering each one. See [`DynamicCacheLocal::with_sketch_and_hasher`].
+    pub fn with_sketch(mem_len: usize) -> Self {
+        Self::with_sketch_and_hasher(mem_len, RandomState::new())
+    }
+}
+
+impl<K: Clone + Eq + Hash, V, S: BuildHasher> DynamicCache<K, V, S> {
+    /// Create and initialize a new cache, using the given hash builder to hash keys. The same
+    /// warnings given for [`HashMap::with_hasher`] apply here.
+    pub fn with_hasher(mem_len: usize, hash_builder: S) -> DynamicCache<K, V, S> {
+        Self::with_capacity_and_hasher(mem_len, 0, hash_builder)
+    }
+
+    /// Create and initialize a new cache with space for at least `capacity` tracked keys, using
+    /// the given hash builder to hash keys. The same warnings given for
+    /// [`HashMap::with_capacity_and_hasher`] apply here.
+    pub fn with_capacity_and_hasher(
+        mem_len: usize,
+        capacity: usize,
+        hash_builder: S,
+    ) -> DynamicCache<K, V, S> {
+        Self::from_local(DynamicCacheLocal::with_capacity_and_hasher(
+            mem_len,
+            capacity,
+            hash_builder,
+        ))
+    }
+
+    /// Create and initialize a new cache that counts requests with a compact sketch, instead of
+    /// remembering each one, using the given hash builder to hash keys. See
+    /// [`DynamicCacheLocal::with_sketch_and_hasher`].
+    pub fn with_sketch_and_hasher(mem_len: usize, hash_builder: S) -> DynamicCache<K, V, S> {
+        Self::from_local(DynamicCacheLocal::with_sketch_and_hasher(
+            mem_len,
+            hash_builder,
+        ))
+    }
+
+    fn from_local(mut cache: DynamicCacheLocal<K, V, S>) -> Self {
+        cache.evictions.defer();
+        Self {
+            cache: Arc::new(Mutex::new(cache)),
+            loading: Arc::default(),
+        }
+    }
+
+    /// Run `f` on the locked cache, then report any values it removed to the eviction listener
+    /// once the lock has been released.
+    fn with_lock<R>(&self, f: impl FnOnce(&mut DynamicCacheLocal<K, V, S>) -> R) -> R {
+        let mut cache = self.cache.lock().unwrap();
+        let ret = f(&mut cache);
+        let evicted = cache.evictions.take_deferred();
+        drop(cache);
+        if let Some(evicted) = evicted {
+            evicted.report();
+        }
+        ret
+    }
+
+    /// Attempt to retrieve a value from the cache. This updates the cache's memory of what values
+    /// have been requested.
+    ///
+    /// The key may be any borrowed form of the cache's key type, but [`Hash`] and [`Eq`] on the
+    /// borrowed form *must* match those for the key type.
+    pub fn get<Q>(&self, key: &Q) -> Option<Arc<V>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq + ToOwned<Owned = K>,
+    {
+        self.with_lock(|cache| cache.get(key))
+    }
+
+    /// Attempt to remove a value from the cache.
+    ///
+    /// This will remove the value itself from the cache, but doesn't change the cache's stored
+    /// request history. This means that any new [`get_or_insert`] calls will re-load the value
+    /// into the cache.
+    ///
+    /// The key may be any borrowed form of the cache's key type, but [`Hash`] and [`Eq`] on the
+    /// borrowed form *must* match those for the key type.
+    pub fn pop<Q>(&self, key: &Q) -> Option<Arc<V>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.with_lock(|cache| cache.pop(key))
+    }
+
+    /// Insert a value into the cache. If the value is already present, an `Arc<V>` of the stored
+    /// value is returned. If the value is not stored but has been requested more than once, then
+    /// it is stored and returned. If the value is not stored and hasn't been requested more than
+    /// once, it is not stored and is simply returned, wrapped as an `Arc<V>`.
+    ///
+    /// The key may be any borrowed form of the cache's key type, but [`Hash`] and [`Eq`] on the
+    /// borrowed form *must* match those for the key type.
+    pub fn insert<Q>(&self, key: &Q, value: V) -> Arc<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.with_lock(|cache| cache.insert(key, value))
+    }
+
+    /// Insert a value into the cache, exactly like [`insert`](Self::insert), except that a newly
+    /// stored value expires after `ttl` instead of the cache's default time-to-live.
+    ///
+    /// The key may be any borrowed form of the cache's key type, but [`Hash`] and [`Eq`] on the
+    /// borrowed form *must* match those for the key type.
+    pub fn insert_with_ttl<Q>(&self, key: &Q, value: V, ttl: Duration) -> Arc<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.with_lock(|cache| cache.insert_with_ttl(key, value, ttl))
+    }
+
+    /// Store a value that stays in the cache regardless of how it's requested, replacing any value
+    /// already stored for the key, and return it. See [`DynamicCacheLocal::pin`].
+    pub fn pin(&self, key: K, value: V) -> Arc<V> {
+        self.with_lock(|cache| cache.pin(key, value))
+    }
+
+    /// Unpin a key's value, returning whether it was pinned. See [`DynamicCacheLocal::unpin`].
+    ///
+    /// The key may be any borrowed form of the cache's key type, but [`Hash`] and [`Eq`] on the
+    /// borrowed form *must* match those for the key type.
+    pub fn unpin<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.with_lock(|cache| cache.unpin(key))
+    }
+
+    /// Check if a key's value is [pinned](Self::pin). This does not update the cache's memory.
+    ///
+    /// The key may be any borrowed form of the cache's key type, but [`Hash`] and [`Eq`] on the
+    /// borrowed form *must* match those for the key type.
+    pub fn is_pinned<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.cache.lock().unwrap().is_pinned(key)
+    }
+
+    /// Fetch an item via the cache, potentially filling in the cache on a miss via the function
+    /// `f`. The cache is unlocked while calling the function, so `f` may be called more than once
+    /// with the same parameters if there are several threads using the cache.
+    ///
+    /// The key may be any borrowed form of the cache's key type, but [`Hash`] and [`Eq`] on the
+    /// borrowed form *must* match those for the key type.
+    pub fn get_or_insert<Q, F>(&self, key: &Q, f: F) -> Arc<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq + ToOwned<Owned = K>,
+        F: FnOnce() -> V,
+    {
+        self.get(key).unwrap_or_else(|| self.insert(key, f()))
+    }
+
+    /// Fetch an item via the cache, potentially filling in the cache on a miss via the function
+    /// `f`. Unlike [`get_or_insert`](Self::get_or_insert), concurrent requests for the same key
+    /// share a single call to a loading function: while one thread runs its `f`, any other thread
+    /// requesting the same key through this method waits for it and receives the same `Arc<V>`,
+    /// whether or not the value ended up being stored in the cache.
+    ///
+    /// If the loading function panics, the panic is propagated to its caller and one of the
+    /// waiting threads runs its own `f` instead.
+    ///
+    /// The key may be any borrowed form of the cache's key type, but [`Hash`] and [`Eq`] on the
+    /// borrowed form *must* match those for the key type.
+    pub fn get_or_insert_dedup<Q, F>(&self, key: &Q, f: F) -> Arc<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq + ToOwned<Owned = K>,
+        F: FnOnce() -> V,
+    {
+        let mut first_attempt = true;
+        loop {
+            match self.lookup_or_join(key, first_attempt) {
+                Ok(v) => return v,
+                Err(Role::Follower(flight)) => {
+                    if let Some(v) = flight.wait() {
+                        return v;
+                    }
+                }
+                Err(Role::Leader(leader)) => return self.finish_load(key, leader, f()),
+            }
+            first_attempt = false;
+        }
+    }
+
+    /// Fetch an item via the cache, potentially filling in the cache on a miss by awaiting the
+    /// future returned by `f`. This is the async version of
+    /// [`get_or_insert_dedup`](Self::get_or_insert_dedup), and shares loads with it: concurrent
+    /// requests for the same key made through either method wait for a single load and receive
+    /// the same `Arc<V>`. The cache is never locked while awaiting.
+    ///
+    /// If the future performing the load is dropped before finishing, the load is abandoned and
+    /// one of the waiting requests starts its own load instead.
+    ///
+    /// The key may be any borrowed form of the cache's key type, but [`Hash`] and [`Eq`] on the
+    /// borrowed form *must* match those for the key type.
+    #[cfg(feature = "async")]
+    pub async fn get_or_insert_async<Q, F, Fut>(&self, key: &Q, f: F) -> Arc<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq + ToOwned<Owned = K>,
+        F: FnOnce() -> Fut,
+        Fut: std::future::Future<Output = V>,
+    {
+        let mut first_attempt = true;
+        loop {
+            match self.lookup_or_join(key, first_attempt) {
+                Ok(v) => return v,
+                Err(Role::Follower(flight)) => {
+                    if let Some(v) = flight::FlightWait::new(flight).await {
+                        return v;
+                    }
+                }
+                Err(Role::Leader(leader)) => {
+                    let value = f().await;
+                    return self.finish_load(key, leader, value);
+                }
+            }
+            first_attempt = false;
+        }
+    }
+
+    /// Look up a value, or join the in-progress load for it on a miss, starting a new load if
+    /// there isn't one. Only the first attempt counts as a request; retries after an abandoned
+    /// load just look for a value stored in the meantime.
+    fn lookup_or_join<Q>(&self, key: &Q, first_attempt: bool) -> Result<Arc<V>, Role<'_, K, V>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq + ToOwned<Owned = K>,
+    {
+        // Recording the request can evict values, and the eviction listener must be called before
+        // this request leads a load, in case the listener requests the same key.
+        if first_attempt {
+            if let Some(v) = self.get(key) {
+                return Ok(v);
+            }
+        }
+        // Loads are finished while holding the loading lock, so checking again under it ensures
+        // a load that finished since the first lookup isn't repeated.
+        let mut loading = self.loading.lock().unwrap();
+        match self.peek(key) {
+            Some(v) => Ok(v),
+            None => Err(flight::join_or_lead(&self.loading, &mut loading, key)),
+        }
+    }
+
+    /// Insert the result of a load and hand it to every request waiting on it.
+    fn finish_load<Q>(&self, key: &Q, leader: flight::Leader<'_, K, V>, value: V) -> Arc<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let mut loading = self.loading.lock().unwrap();
+        let mut cache = self.cache.lock().unwrap();
+        let value = cache.insert(key, value);
+        let evicted = cache.evictions.take_deferred();
+        drop(cache);
+        leader.finish(&mut loading, value.clone());
+        drop(loading);
+        if let Some(evicted) = evicted {
+            evicted.report();
+        }
+        value
+    }
+
+    /// Fetch an item via the cache, potentially filling in the cache on a miss via the fallible
+    /// function `f`. If `f` fails, the error is returned and nothing is stored. The request is
+    /// still remembered and counted as a miss, just as it would be by [`get`](Self::get). The
+    /// cache is unlocked while calling the function, so `f` may be called more than once with the
+    /// same parameters if there are several threads using the cache.
+    ///
+    /// The key may be any borrowed form of the cache's key type, but [`Hash`] and [`Eq`] on the
+    /// borrowed form *must* match those for the key type.
+    pub fn get_or_try_insert<Q, E, F>(&self, key: &Q, f: F) -> Result<Arc<V>, E>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq + ToOwned<Owned = K>,
+        F: FnOnce() -> Result<V, E>,
+    {
+        match self.get(key) {
+            Some(v) => Ok(v),
+            None => Ok(self.insert(key, f()?)),
+        }
+    }
+
+    /// Retrieve a value from the cache without updating the cache's memory of what values have
+    /// been requested, or its hit/miss metrics.
+    ///
+    /// The key may be any borrowed form of the cache's key type, but [`Hash`] and [`Eq`] on the
+    /// borrowed form *must* match those for the key type.
+    pub fn peek<Q>(&self, key: &Q) -> Option<Arc<V>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.cache.lock().unwrap().peek(key)
+    }
+
+    /// Check if a value is currently stored in the cache. This does not update the cache's memory
+    /// of what values have been requested.
+    ///
+    /// The key may be any borrowed form of the cache's key type, but [`Hash`] and [`Eq`] on the
+    /// borrowed form *must* match those for the key type.
+    pub fn contains<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.cache.lock().unwrap().contains(key)
+    }
+
+    /// Check if there are any requests for a key in the cache's recent request memory, whether or
+    /// not a value is stored for it. This does not update the cache's memory.
+    ///
+    /// With a [memory duration](Self::set_mem_duration), requests older than the duration are only
+    /// forgotten when the next request is made, so they may still be counted here. With a
+    /// [sketch](Self::with_sketch), this checks the sketch's approximate count, so it can be true
+    /// for a key that was never requested. See [`DynamicCacheLocal::is_tracked`].
+    ///
+    /// The key may be any borrowed form of the cache's key type, but [`Hash`] and [`Eq`] on the
+    /// borrowed form *must* match those for the key type.
+    pub fn is_tracked<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.cache.lock().unwrap().is_tracked(key)
+    }
+
+    /// Get the number of requests for a key in the cache's recent request memory. This does not
+    /// update the cache's memory.
+    ///
+    /// With a [memory duration](Self::set_mem_duration), requests older than the duration are only
+    /// forgotten when the next request is made, so they may still be counted here. With a
+    /// [sketch](Self::with_sketch), this is the sketch's approximate count, so it can be nonzero
+    /// for a key that was never requested. See [`DynamicCacheLocal::recent_request_count`].
+    ///
+    /// The key may be any borrowed form of the cache's key type, but [`Hash`] and [`Eq`] on the
+    /// borrowed form *must* match those for the key type.
+    pub fn recent_request_count<Q>(&self, key: &Q) -> u32
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.cache.lock().unwrap().recent_request_count(key)
+    }
+
+    /// Get the number of items currently stored in the cache.
+    pub fn size(&self) -> usize {
+        self.cache.lock().unwrap().size()
+    }
+
+    /// Get the total weight of all items currently stored in the cache. Without a
+    /// [weigher](Self::set_weigher), every item weighs 1 and this is the same as
+    /// [`size`](Self::size).
+    pub fn weight(&self) -> usize {
+        self.cache.lock().unwrap().weight()
+    }
+
+    /// Get the maximum total weight of stored items. This is `usize::MAX`, meaning unlimited,
+    /// unless changed with [`set_max_weight`](Self::set_max_weight).
+    pub fn max_weight(&self) -> usize {
+        self.cache.lock().unwrap().max_weight()
+    }
+
+    /// Change the maximum total weight of stored items. If the cache is currently heavier than
+    /// this, the least recently requested items are removed immediately until it fits.
+    pub fn set_max_weight(&self, max_weight: usize) {
+        self.with_lock(|cache| cache.set_max_weight(max_weight))
+    }
+
+    /// Set the function used to weigh stored items, replacing the default weight of 1 per item.
+    /// The weights of items already in the cache are recalculated, and items are removed if the
+    /// cache is now over its maximum weight.
+    pub fn set_weigher<F>(&self, weigher: F)
+    where
+        F: Fn(&K, &V) -> usize + Send + Sync + 'static,
+    {
+        self.with_lock(|cache| cache.set_weigher(weigher))
+    }
+
+    /// Get the maximum number of items that can be stored in the cache at once. This is
+    /// `usize::MAX`, meaning only limited by the memory length, unless changed with
+    /// [`set_max_entries`](Self::set_max_entries).
+    pub fn max_entries(&self) -> usize {
+        self.cache.lock().unwrap().max_entries()
+    }
+
+    /// Change the maximum number of items that can be stored in the cache at once. If more items
+    /// than this are currently stored, the least recently requested items are removed
+    /// immediately.
+    pub fn set_max_entries(&self, max_entries: usize) {
+        self.with_lock(|cache| cache.set_max_entries(max_entries))
+    }
+
+    /// Get the default time-to-live for newly stored values. This is `None`, meaning values don't
+    /// expire, unless changed with [`set_ttl`](Self::set_ttl).
+    pub fn ttl(&self) -> Option<Duration> {
+        self.cache.lock().unwrap().ttl()
+    }
+
+    /// Change the default time-to-live for newly stored values. Values already in the cache keep
+    /// their current expiration time.
+    ///
+    /// Expired values are treated as missing, and are dropped the next time they're requested or
+    /// inserted again. The cache's memory of requests for an expired value is kept, so the reloaded
+    /// value is usually stored again right away.
+    pub fn set_ttl(&self, ttl: Option<Duration>) {
+        self.cache.lock().unwrap().set_ttl(ttl)
+    }
+
+    /// Replace the clock used for time-to-live expiration and time-limited request memory, which is
+    /// [`SystemClock`] by default.
+    pub fn set_clock<C: Clock + 'static>(&self, clock: C) {
+        self.cache.lock().unwrap().set_clock(clock)
+    }
+
+    /// Get the length of the cache's recent request memory.
+    pub fn mem_len(&self) -> usize {
+        self.cache.lock().unwrap().mem_len()
+    }
+
+    /// Get the number of recent requests a key needs before its value is stored in the cache.
+    pub fn admit_threshold(&self) -> u32 {
+        self.cache.lock().unwrap().admit_threshold()
+    }
+
+    /// Change the number of recent requests a key needs before its value is stored in the cache.
+    /// A threshold of 1 stores values on their first request. Values that are already stored are
+    /// not affected.
+    ///
+    /// See [`DynamicCacheLocal::set_admit_threshold`] for which requests count.
+    pub fn set_admit_threshold(&self, threshold: u32) {
+        self.cache.lock().unwrap().set_admit_threshold(threshold)
+    }
+
+    /// Change the length of the cache's recent request memory. Some contents of the cache may be
+    /// removed immediately if the new memory length is shorter than the old memory length.
+    pub fn set_mem_len(&self, new_len: usize) {
+        self.with_lock(|cache| cache.set_mem_len(new_len))
+    }
+
+    /// Get the bounds the memory length is kept within, if it's [tuned
+    /// automatically](Self::set_auto_mem_len).
+    pub fn auto_mem_len(&self) -> Option<RangeInclusive<usize>> {
+        self.cache.lock().unwrap().auto_mem_len()
+    }
+
+    /// Tune the memory length automatically, keeping it within the given bounds, or stop tuning
+    /// it with `None`. See [`DynamicCacheLocal::set_auto_mem_len`].
+    pub fn set_auto_mem_len(&self, bounds: Option<RangeInclusive<usize>>) {
+        self.with_lock(|cache| cache.set_auto_mem_len(bounds))
+    }
+
+    /// Check whether values that leave the cache are kept as weak references. See
+    /// [`DynamicCacheLocal::set_weak_values`].
+    pub fn weak_values(&self) -> bool {
+        self.cache.lock().unwrap().weak_values()
+    }
+
+    /// Choose whether values that leave the cache are kept as weak references. See
+    /// [`DynamicCacheLocal::set_weak_values`].
+    pub fn set_weak_values(&self, enabled: bool)
+    where
+        S: Clone,
+    {
+        self.with_lock(|cache| cache.set_weak_values(enabled))
+    }
+
+    /// Get how long requests are kept in the cache's recent request memory. This is `None`,
+    /// meaning requests are only forgotten once the memory is full, unless changed with
+    /// [`set_mem_duration`](Self::set_mem_duration).
+    pub fn mem_duration(&self) -> Option<Duration> {
+        self.cache.lock().unwrap().mem_duration()
+    }
+
+    /// Change how long requests are kept in the cache's recent request memory. Requests older than
+    /// this are forgotten even if the memory isn't full, along with keys that have no newer
+    /// requests. The memory length still applies as well; set it
+    /// very high with [`set_mem_len`](Self::set_mem_len) to only forget requests based on time.
+    ///
+    /// Requests made before a duration was set are only ever forgotten once the memory is full.
+    pub fn set_mem_duration(&self, duration: Option<Duration>) {
+        self.with_lock(|cache| cache.set_mem_duration(duration))
+    }
+
+    /// Get a snapshot of all values stored in the cache, in arbitrary order. This does not update
+    /// the cache's memory of what values have been requested.
+    pub fn entries(&self) -> Vec<(K, Arc<V>)> {
+        let cache = self.cache.lock().unwrap();
+        cache.iter().map(|(k, v)| (k.clone(), v.clone())).collect()
+    }
+
+    /// Get a snapshot of the keys of all values stored in the cache, in arbitrary order.
+    pub fn keys(&self) -> Vec<K> {
+        self.cache.lock().unwrap().keys().cloned().collect()
+    }
+
+    /// Get a snapshot of the keys in the cache's recent request memory, one for each remembered
+    /// request, from the most recent request to the oldest.
+    pub fn history(&self) -> Vec<K> {
+        self.cache.lock().unwrap().history().cloned().collect()
+    }
+
+    /// Get every key in the cache's recent request memory along with its recent request count.
+    /// See [`DynamicCacheLocal::export_history`].
+    pub fn export_history(&self) -> Vec<(K, u32)> {
+        self.cache.lock().unwrap().export_history()
+    }
+
+    /// Add requests to the cache's memory, as exported by
+    /// [`export_history`](Self::export_history). See [`DynamicCacheLocal::import_history`].
+    pub fn import_history<I>(&self, history: I)
+    where
+        I: IntoIterator<Item = (K, u32)>,
+    {
+        self.with_lock(|cache| cache.import_history(history))
+    }
+
+    /// Clear out all memory in the cache, returning all values that were stored in it.
+    pub fn drain(&self) -> Vec<(K, Arc<V>)> {
+        self.cache.lock().unwrap().drain().collect()
+    }
+
+    /// Clear out all stored values and all memory in the cache, including [pinned](Self::pin)
+    /// values. Use [`clear_unpinned`](Self::clear_unpinned) to keep them.
+    pub fn clear_cache(&self) {
+        self.with_lock(|cache| cache.clear_cache())
+    }
+
+    /// Clear out all memory in the cache and all stored values, except for [pinned](Self::pin)
+    /// values, which stay stored.
+    pub fn clear_unpinned(&self) {
+        self.with_lock(|cache| cache.clear_unpinned())
+    }
+
+    /// Set a function to be called with every stored value that leaves the cache, along with its
+    /// key and the reason it was removed. This covers values forgotten along with their request
+    /// history, expired, popped, replaced, dropped for capacity, or cleared out, but not values
+    /// returned by [`drain`](Self::drain).
+    ///
+    /// The listener is only called after the cache has been unlocked, so it's free to use the
+    /// cache itself.
+    pub fn set_eviction_listener<F>(&self, listener: F)
+    where
+        F: Fn(K, Arc<V>, EvictionReason) + Send + Sync + 'static,
+    {
+        self.cache.lock().unwrap().set_eviction_listener(listener)
+    }
+
+    /// Get the cache metrics as a pair `(hits, misses)`.
+    pub fn hits_misses(&self) -> (u64, u64) {
+        let cache = self.cache.lock().unwrap();
+        (cache.hits(), cache.misses())
+    }
+
+    /// Get a snapshot of the cache's statistics.
+    pub fn stats(&self) -> CacheStats {
+        self.cache.lock().unwrap().stats()
+    }
+
+    /// Reset all of the cache's metrics, including everything counted by [`stats`](Self::stats).
+    pub fn reset_metrics(&self) {
+        self.cache.lock().unwrap().reset_metrics()
+    }
+}
+
+impl<K: Clone + Eq + Hash, V, S: BuildHasher + Default> Default for DynamicCache<K, V, S> {
+    /// Create an empty cache with a memory length of [`DEFAULT_MEM_LEN`].
+    fn default() -> Self {
+        Self::with_hasher(DEFAULT_MEM_LEN, S::default())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::prelude::*;
+    use std::sync::Mutex;
+
+    /// A clock that only moves when told to.
+    #[derive(Clone)]
+    struct ManualClock(Arc<Mutex<Instant>>);
+
+    impl ManualClock {
+        fn new() -> Self {
+            Self(Arc::new(Mutex::new(Instant::now())))
+        }
+
+        fn advance(&self, by: Duration) {
+            *self.0.lock().unwrap() += by;
+        }
+    }
+
+    impl Clock for ManualClock {
+        fn now(&self) -> Instant {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    /// Run the body once with `Local` as the cache handing out `Arc`s and `Ptr` as `Arc`, then
+    /// once more with both switched over to `Rc`.
+    macro_rules! with_each_pointer {
+        ($($body:tt)*) => {{
+            {
+                #[allow(unused_imports)]
+                use std::sync::Arc as Ptr;
+                type Local<K, V, S = RandomState> = DynamicCacheLocal<K, V, S>;
+                $($body)*
+            }
+            {
+                #[allow(unused_imports)]
+                use std::rc::Rc as Ptr;
+                type Local<K, V, S = RandomState> = RcDynamicCacheLocal<K, V, S>;
+                $($body)*
+            }
+        }};
+    }
+
+    #[test]
+    fn fetch_test() {
+        let (key, val) = (0, String::from("0"));
+        let cache = DynamicCache::new(8);
+        assert_eq!(cache.size(), 0);
+        assert_eq!(cache.mem_len(), 8);
+        assert_eq!(cache.hits_misses(), (0, 0));
+
+        assert!(
+            cache.get(&key).is_none(),
+            "First `get` should have nothing in cache"
+        );
+        assert_eq!(cache.size(), 0);
+        assert_eq!(cache.hits_misses(), (0, 1));
+
+        assert!(
+            cache.insert(&key, val.clone()).as_ref() == &val,
+            "Insert should return right value"
+        );
+        assert_eq!(cache.size(), 0);
+        assert_eq!(cache.hits_misses(), (0, 1));
+
+        assert!(
+            cache.get(&key).is_none(),
+            "Second `get` should still have nothing in cache"
+        );
+        assert_eq!(cache.size(), 0);
+        assert_eq!(cache.hits_misses(), (0, 2));
 
         assert!(
             cache.insert(&key, val.clone()).as_ref() == &val,
             "Insert should return right value"
         );
+        assert_eq!(cache.size(), 1);
+        assert_eq!(cache.hits_misses(), (0, 2));
+
+        assert!(
+            cache.get(&key).is_some_and(|x| x.as_ref() == &val),
+            "Third `get` should have a value in cache"
+        );
+        assert_eq!(cache.size(), 1);
+        assert_eq!(cache.hits_misses(), (1, 2));
+
+        assert_eq!(cache.mem_len(), 8);
+    }
+
+    #[test]
+    fn custom_hasher_test() {
+        use std::collections::hash_map::DefaultHasher;
+        use std::hash::BuildHasherDefault;
+        type Hasher = BuildHasherDefault<DefaultHasher>;
+
+        with_each_pointer! {
+            let mut local: Local<u32, String, Hasher> = Local::default();
+            assert_eq!(local.mem_len(), DEFAULT_MEM_LEN);
+            assert!(local.get(&1).is_none());
+            local.insert(&1, String::from("1"));
+            assert_eq!(local.size(), 0);
+            assert_eq!(local.get_or_insert(&1, || String::from("1")).as_str(), "1");
+            assert_eq!(local.size(), 1);
+            assert_eq!(local.get(&1).as_deref().map(String::as_str), Some("1"));
+            assert_eq!((local.hits(), local.misses()), (1, 2));
+            local.set_mem_len(2);
+            assert_eq!(local.size(), 1);
+            assert_eq!(local.pop(&1).as_deref().map(String::as_str), Some("1"));
+            assert_eq!(local.size(), 0);
+            local.reset_metrics();
+            local.clear_cache();
+            assert_eq!((local.hits(), local.misses()), (0, 0));
+        }
+
+        let cache = DynamicCache::with_capacity_and_hasher(8, 16, Hasher::default());
+        for _ in 0..2 {
+            assert_eq!(cache.get_or_insert(&"key", || 5).as_ref(), &5);
+        }
+        assert_eq!(cache.size(), 1);
+        assert_eq!(cache.hits_misses(), (0, 2));
+        assert_eq!(cache.get(&"key").as_deref(), Some(&5));
+        assert_eq!(cache.hits_misses(), (1, 2));
+        cache.set_mem_len(4);
+        assert_eq!(cache.mem_len(), 4);
+        assert_eq!(cache.pop(&"key").as_deref(), Some(&5));
+        assert_eq!(cache.size(), 0);
+        cache.clear_cache();
+        cache.reset_metrics();
+        assert_eq!(cache.size(), 0);
+        assert_eq!(cache.hits_misses(), (0, 0));
+
+        let cache: DynamicCache<u8, u8, Hasher> = DynamicCache::default();
+        assert_eq!(cache.mem_len(), DEFAULT_MEM_LEN);
+    }
+
+    #[test]
+    fn pop_size_test() {
+        with_each_pointer! {
+            let mut cache = Local::new(8);
+            for key in [1, 1, 2, 2] {
+                cache.get_or_insert(&key, || key * 10);
+            }
+            assert_eq!(cache.size(), 2);
+            // Popping a stored key frees its slot, popping it again changes nothing
+            assert_eq!(cache.pop(&1).as_deref(), Some(&10));
+            assert_eq!(cache.size(), 1);
+            assert_eq!(cache.pop(&1), None);
+            assert_eq!(cache.size(), 1);
+            // Popping a key that's only tracked doesn't touch the count either
+            cache.get(&3);
+            assert_eq!(cache.pop(&3), None);
+            assert_eq!(cache.size(), 1);
+            assert_eq!(cache.pop(&2).as_deref(), Some(&20));
+            assert_eq!(cache.size(), 0);
+        }
+    }
+
+    #[test]
+    fn borrowed_key_test() {
+        use std::path::{Path, PathBuf};
+
+        with_each_pointer! {
+            let mut local: Local<String, usize> = Local::new(8);
+            assert!(local.get("abc").is_none());
+            assert!(!local.contains("abc"));
+            assert_eq!(local.get_or_insert("abc", || 3).as_ref(), &3);
+            assert!(local.contains("abc"));
+            assert_eq!(local.get("abc").as_deref(), Some(&3));
+            assert_eq!(local.get(&String::from("abc")).as_deref(), Some(&3));
+            assert_eq!(local.pop("abc").as_deref(), Some(&3));
+            assert!(!local.contains("abc"));
+            assert_eq!(local.insert("abc", 4).as_ref(), &4);
+            assert!(local.contains("abc"));
+        }
+
+        let cache: DynamicCache<PathBuf, Vec<u8>> = DynamicCache::new(8);
+        let path = Path::new("/dict/a.zst");
+        assert!(cache.get(path).is_none());
+        cache.insert(path, vec![1]);
+        assert!(!cache.contains(path));
+        assert!(cache.get(path).is_none());
+        cache.insert(path, vec![1]);
+        assert!(cache.contains(path));
+        assert_eq!(cache.get_or_insert(path, Vec::new).as_slice(), &[1]);
+        assert_eq!(cache.pop(path).as_deref(), Some(&vec![1]));
+        assert_eq!(cache.size(), 0);
+    }
+
+    #[test]
+    fn admit_threshold_test() {
+        with_each_pointer! {
+            let mut cache = Local::new(8);
+            assert_eq!(cache.admit_threshold(), DEFAULT_ADMIT_THRESHOLD);
+            assert!(format!("{:?}", cache).contains("admit_threshold: 2"));
+
+            // The default threshold stores on any second request while the key is remembered,
+            // even once the first has left the memory
+            let mut short = Local::new(2);
+            for k in [0, 1, 0] {
+                short.get(&k);
+            }
+            short.insert(&0, 0);
+            assert!(short.contains(&0));
+
+            cache.set_admit_threshold(3);
+            for _ in 0..2 {
+                cache.get_or_insert(&0, || 0);
+                assert_eq!(cache.size(), 0);
+            }
+            cache.get_or_insert(&0, || 0);
+            assert_eq!(cache.size(), 1);
+            assert!(cache.get(&0).is_some());
+
+            // With any other threshold, requests that fell out of memory don't count towards
+            // admission, even while the key is still remembered through later requests
+            for i in 1..=8 {
+                cache.get(&i);
+            }
+            assert_eq!(cache.size(), 0);
+            for i in 0..3 {
+                assert!(cache.get(&100).is_none());
+                cache.insert(&100, 100);
+                assert_eq!(cache.size(), 0);
+                for j in 0..3 {
+                    cache.get(&(10 + 3 * i + j));
+                }
+            }
+            assert!(cache.get(&100).is_none());
+            cache.insert(&100, 100);
+            assert_eq!(cache.size(), 0);
+
+            cache.set_admit_threshold(0);
+            assert_eq!(cache.admit_threshold(), 1);
+            assert!(cache.get(&200).is_none());
+            cache.insert(&200, 200);
+            assert_eq!(cache.get(&200).as_deref(), Some(&200));
+        }
+
+        let shared = DynamicCache::new(8);
+        shared.set_admit_threshold(1);
+        assert_eq!(shared.admit_threshold(), 1);
+        shared.get_or_insert(&0, || 0);
+        assert_eq!(shared.size(), 1);
+    }
+
+    #[test]
+    fn try_insert_test() {
+        let cache = DynamicCache::new(8);
+        let err: Result<_, &str> = cache.get_or_try_insert(&0, || Err("bad"));
+        assert_eq!(err, Err("bad"));
         assert_eq!(cache.size(), 0);
         assert_eq!(cache.hits_misses(), (0, 1));
 
-        assert!(
-            cache.get(&key).is_none(),
-            "Second `get` should still have nothing in cache"
-        );
+        // The failed request still counts towards admission, but its error is never cached
+        let err: Result<_, &str> = cache.get_or_try_insert(&0, || Err("bad"));
+        assert_eq!(err, Err("bad"));
         assert_eq!(cache.size(), 0);
         assert_eq!(cache.hits_misses(), (0, 2));
 
-        assert!(
-            cache.insert(&key, val.clone()).as_ref() == &val,
-            "Insert should return right value"
+        let ok = cache.get_or_try_insert(&0, || Ok::<_, &str>(String::from("0")));
+        assert_eq!(ok.unwrap().as_str(), "0");
+        assert_eq!(cache.size(), 1);
+        assert_eq!(cache.hits_misses(), (0, 3));
+
+        let ok = cache.get_or_try_insert(&0, || Err("not called"));
+        assert_eq!(ok.unwrap().as_str(), "0");
+        assert_eq!(cache.hits_misses(), (1, 3));
+
+        with_each_pointer! {
+            let mut local = Local::new(8);
+            assert!(local.get_or_try_insert(&1, || Ok::<_, ()>(1)).is_ok());
+            assert!(local.get_or_try_insert(&1, || Err(())).is_err());
+            assert_eq!(local.size(), 0);
+            assert_eq!(*local.get_or_try_insert(&1, || Ok::<_, ()>(1)).unwrap(), 1);
+            assert_eq!(local.size(), 1);
+            assert_eq!((local.hits(), local.misses()), (0, 3));
+        }
+    }
+
+    #[test]
+    fn dedup_test() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Barrier;
+        use std::thread;
+        use std::time::Duration;
+
+        let cache = DynamicCache::new(64);
+        let calls = Arc::new(AtomicUsize::new(0));
+        let barrier = Arc::new(Barrier::new(8));
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let (cache, calls, barrier) = (cache.clone(), calls.clone(), barrier.clone());
+                thread::spawn(move || {
+                    barrier.wait();
+                    cache.get_or_insert_dedup(&0, || {
+                        calls.fetch_add(1, Ordering::SeqCst);
+                        thread::sleep(Duration::from_millis(100));
+                        String::from("0")
+                    })
+                })
+            })
+            .collect();
+        let values: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert!(values.iter().all(|v| Arc::ptr_eq(v, &values[0])));
+        assert_eq!(cache.hits_misses(), (0, 8));
+        assert_eq!(cache.size(), 1);
+
+        // A panicking loader hands the load over to a waiting thread
+        let leader = {
+            let cache = cache.clone();
+            thread::spawn(move || {
+                cache.get_or_insert_dedup(&1, || {
+                    thread::sleep(Duration::from_millis(100));
+                    panic!("loader failed");
+                })
+            })
+        };
+        thread::sleep(Duration::from_millis(20));
+        let follower = {
+            let cache = cache.clone();
+            thread::spawn(move || cache.get_or_insert_dedup(&1, || String::from("1")))
+        };
+        assert!(leader.join().is_err());
+        assert_eq!(follower.join().unwrap().as_str(), "1");
+        assert_eq!(
+            cache.get_or_insert_dedup(&1, || String::from("x")).as_str(),
+            "1"
         );
+        assert!(cache.loading.lock().unwrap().is_empty());
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn async_test() {
+        use futures::channel::oneshot;
+        use futures::executor::block_on;
+        use futures::future::pending;
+        use futures::{join, poll};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        fn assert_send<T: Send>(_: &T) {}
+
+        let cache = DynamicCache::new(64);
+        let calls = AtomicUsize::new(0);
+        let (tx, rx) = oneshot::channel::<()>();
+        let load = |v: &'static str| {
+            let calls = &calls;
+            move || async move {
+                calls.fetch_add(1, Ordering::SeqCst);
+                String::from(v)
+            }
+        };
+        let leader = cache.get_or_insert_async(&0, || async {
+            calls.fetch_add(1, Ordering::SeqCst);
+            rx.await.unwrap();
+            String::from("0")
+        });
+        assert_send(&leader);
+        let (a, b, c, ()) = block_on(async {
+            join!(
+                leader,
+                cache.get_or_insert_async(&0, load("1")),
+                cache.get_or_insert_async(&0, load("2")),
+                async { tx.send(()).unwrap() },
+            )
+        });
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(a.as_str(), "0");
+        assert!(Arc::ptr_eq(&a, &b) && Arc::ptr_eq(&a, &c));
         assert_eq!(cache.size(), 1);
+
+        // Dropping the leading future hands the load over to a waiting one
+        block_on(async {
+            let mut leader = Box::pin(cache.get_or_insert_async(&1, pending::<String>));
+            assert!(poll!(leader.as_mut()).is_pending());
+            let mut follower = Box::pin(cache.get_or_insert_async(&1, load("1")));
+            assert!(poll!(follower.as_mut()).is_pending());
+            drop(leader);
+            assert_eq!(follower.await.as_str(), "1");
+        });
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert!(cache.loading.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn weight_test() {
+        with_each_pointer! {
+            let mut cache: Local<u32, Vec<u8>> = Local::new(64);
+            let load = |cache: &mut Local<u32, Vec<u8>>, key: u32, len: usize| {
+                cache.get_or_insert(&key, || vec![0; len]);
+                cache.get_or_insert(&key, || vec![0; len]);
+            };
+            load(&mut cache, 0, 10);
+            load(&mut cache, 1, 10);
+            assert_eq!(cache.weight(), 2);
+            cache.set_weigher(|_, v| v.len());
+            assert_eq!(cache.weight(), 20);
+            cache.set_max_weight(50);
+            assert_eq!(cache.max_weight(), 50);
+
+            load(&mut cache, 2, 20);
+            assert_eq!((cache.size(), cache.weight()), (3, 40));
+            // Key 0 is the least recently requested, so it goes first
+            cache.get(&1);
+            cache.get(&2);
+            load(&mut cache, 3, 15);
+            assert_eq!((cache.size(), cache.weight()), (3, 45));
+            assert!(!cache.contains(&0));
+            assert!(cache.contains(&1) && cache.contains(&2) && cache.contains(&3));
+
+            // Too heavy to ever be stored
+            load(&mut cache, 4, 51);
+            assert!(!cache.contains(&4));
+            assert_eq!(cache.weight(), 45);
+
+            cache.set_max_weight(30);
+            assert_eq!((cache.size(), cache.weight()), (1, 15));
+            assert!(cache.contains(&3));
+            assert_eq!(cache.pop(&3).map(|v| v.len()), Some(15));
+            assert_eq!(cache.weight(), 0);
+        }
+
+        let shared: DynamicCache<u32, String> = DynamicCache::new(8);
+        shared.set_weigher(|_, v| v.len());
+        shared.set_max_weight(4);
+        for _ in 0..2 {
+            shared.get_or_insert(&0, || String::from("abc"));
+            shared.get_or_insert(&1, || String::from("de"));
+        }
+        assert_eq!(shared.max_weight(), 4);
+        assert_eq!((shared.size(), shared.weight()), (1, 2));
+        assert!(shared.contains(&1));
+    }
+
+    #[test]
+    fn max_entries_test() {
+        with_each_pointer! {
+            let mut cache = Local::new(16);
+            cache.set_max_entries(2);
+            assert_eq!(cache.max_entries(), 2);
+            for key in [0, 1, 0, 1, 2, 2] {
+                cache.get_or_insert(&key, || key);
+            }
+            assert_eq!(cache.size(), 2);
+            assert!(!cache.contains(&0));
+            assert!(cache.contains(&1) && cache.contains(&2));
+
+            // Key 1 was requested more recently than key 2 here, so key 2 goes
+            for key in [1, 3, 3] {
+                cache.get_or_insert(&key, || key);
+            }
+            assert!(cache.contains(&1) && cache.contains(&3));
+            assert!(!cache.contains(&2));
+
+            cache.set_max_entries(0);
+            assert_eq!(cache.size(), 0);
+            cache.get_or_insert(&1, || 1);
+            assert_eq!(cache.size(), 0);
+        }
+
+        let mut rng = thread_rng();
+        let shared = DynamicCache::new(256);
+        shared.set_admit_threshold(1);
+        shared.set_max_entries(10);
+        for _ in 0..4096 {
+            let key: u8 = rng.gen_range(0, 64);
+            assert_eq!(*shared.get_or_insert(&key, || key), key);
+            assert!(shared.size() <= 10);
+        }
+        assert_eq!(shared.size(), 10);
+    }
+
+    #[test]
+    fn ttl_test() {
+        let clock = ManualClock::new();
+        with_each_pointer! {
+            let mut cache = Local::new(16);
+            cache.set_clock(clock.clone());
+            cache.set_ttl(Some(Duration::from_secs(10)));
+            assert_eq!(cache.ttl(), Some(Duration::from_secs(10)));
+
+            for _ in 0..2 {
+                cache.get_or_insert(&0, || "config v1");
+            }
+            assert_eq!(cache.size(), 1);
+            clock.advance(Duration::from_secs(9));
+            assert_eq!(cache.get(&0).as_deref(), Some(&"config v1"));
+
+            // Expired values are misses, but the request history stays, so the reload is stored
+            clock.advance(Duration::from_secs(1));
+            assert!(!cache.contains(&0));
+            assert!(cache.get(&0).is_none());
+            assert_eq!(cache.size(), 0);
+            assert_eq!((cache.hits(), cache.misses()), (1, 3));
+            cache.insert(&0, "config v2");
+            assert_eq!(cache.get(&0).as_deref(), Some(&"config v2"));
+
+            // Per-insert overrides, including inserting over an expired value without a `get` first
+            for _ in 0..2 {
+                cache.get(&1);
+            }
+            cache.insert_with_ttl(&1, "short", Duration::from_secs(1));
+            clock.advance(Duration::from_secs(1));
+            assert_eq!(*cache.insert(&1, "fresh"), "fresh");
+            assert_eq!(cache.get(&1).as_deref(), Some(&"fresh"));
+            clock.advance(Duration::from_secs(10));
+            assert!(cache.get(&1).is_none());
+
+            cache.set_ttl(None);
+            cache.insert(&1, "forever");
+            clock.advance(Duration::from_secs(1000));
+            assert_eq!(cache.get(&1).as_deref(), Some(&"forever"));
+        }
+
+        let shared = DynamicCache::new(16);
+        shared.set_clock(clock.clone());
+        for _ in 0..2 {
+            shared.get(&0);
+        }
+        shared.insert_with_ttl(&0, 0, Duration::from_secs(5));
+        assert!(shared.contains(&0));
+        clock.advance(Duration::from_secs(5));
+        assert!(!shared.contains(&0));
+    }
+
+    #[test]
+    fn mem_duration_test() {
+        let clock = ManualClock::new();
+        with_each_pointer! {
+            let mut by_count = Local::new(16);
+            let mut by_time = Local::new(16);
+            by_time.set_clock(clock.clone());
+            by_time.set_mem_len(usize::MAX);
+            by_time.set_mem_duration(Some(Duration::from_secs(60)));
+            assert_eq!(by_time.mem_duration(), Some(Duration::from_secs(60)));
+
+            // A quick burst of unrelated requests flushes a count-based memory, but not a time-based one
+            for cache in [&mut by_count, &mut by_time] {
+                cache.get_or_insert(&0, || 0);
+                for i in 1..=100 {
+                    cache.get_or_insert(&i, || i);
+                }
+                cache.get_or_insert(&0, || 0);
+            }
+            assert!(!by_count.contains(&0));
+            assert!(by_time.contains(&0));
+            assert_eq!(by_time.size(), 1);
+
+            // Keys are forgotten once all of their requests are older than the duration, so
+            // requests spread out over more than it aren't stored
+            clock.advance(Duration::from_secs(60));
+            by_time.get_or_insert(&200, || 200);
+            assert!(!by_time.contains(&0));
+            assert_eq!(by_time.size(), 0);
+            clock.advance(Duration::from_secs(60));
+            by_time.get(&201);
+            assert!(!by_time.is_tracked(&200));
+            by_time.get_or_insert(&200, || 200);
+            assert!(!by_time.contains(&200));
+            clock.advance(Duration::from_secs(59));
+            by_time.get_or_insert(&200, || 200);
+            assert!(by_time.contains(&200));
+
+            // The memory length still applies on top of the duration
+            by_time.set_mem_len(2);
+            by_time.get_or_insert(&300, || 300);
+            by_time.get_or_insert(&301, || 301);
+            assert_eq!(by_time.size(), 0);
+        }
+
+        let shared = DynamicCache::new(4);
+        shared.set_clock(clock.clone());
+        shared.set_mem_duration(Some(Duration::from_secs(1)));
+        shared.get(&0);
+        clock.advance(Duration::from_secs(1));
+        shared.get(&1);
+        shared.get(&0);
+        shared.insert(&0, 0);
+        assert_eq!(shared.size(), 0);
+    }
+
+    #[test]
+    fn introspection_test() {
+        let cache = DynamicCache::new(4);
+        assert!(cache.peek(&0).is_none());
+        assert!(!cache.is_tracked(&0));
+        assert_eq!(cache.recent_request_count(&0), 0);
+
+        cache.get_or_insert(&0, || 0);
+        assert!(cache.is_tracked(&0));
+        assert!(!cache.contains(&0));
+        assert_eq!(cache.recent_request_count(&0), 1);
+        cache.get_or_insert(&0, || 0);
+        assert_eq!(cache.recent_request_count(&0), 2);
+
+        // None of these count as requests
+        for _ in 0..10 {
+            assert_eq!(cache.peek(&0).as_deref(), Some(&0));
+            assert!(cache.contains(&0));
+            assert!(cache.is_tracked(&0));
+            assert!(!cache.is_tracked(&1));
+            assert_eq!(cache.recent_request_count(&0), 2);
+        }
         assert_eq!(cache.hits_misses(), (0, 2));
 
-        assert!(
-            cache.get(&key).map_or(false, |x| x.as_ref() == &val),
-            "Third `get` should have a value in cache"
+        for i in 1..=3 {
+            cache.get(&i);
+        }
+        assert_eq!(cache.recent_request_count(&0), 1);
+        cache.get(&4);
+        assert!(!cache.is_tracked(&0));
+        assert!(cache.peek(&0).is_none());
+
+        with_each_pointer! {
+            let mut local: Local<String, u8> = Local::new(4);
+            local.get("a");
+            assert!(local.is_tracked("a"));
+            assert_eq!(local.recent_request_count("a"), 1);
+            assert!(local.peek("a").is_none());
+        }
+    }
+
+    #[test]
+    fn iter_test() {
+        with_each_pointer! {
+            let mut cache = Local::new(8);
+            for key in [0, 1, 0, 2, 1, 3] {
+                cache.get_or_insert(&key, || key * 10);
+            }
+            let mut entries: Vec<_> = cache.iter().map(|(k, v)| (*k, **v)).collect();
+            entries.sort();
+            assert_eq!(entries, [(0, 0), (1, 10)]);
+            let mut keys: Vec<_> = cache.keys().copied().collect();
+            keys.sort();
+            assert_eq!(keys, [0, 1]);
+            assert_eq!(
+                cache.history().copied().collect::<Vec<_>>(),
+                [3, 1, 2, 0, 1, 0]
+            );
+            assert_eq!((cache.hits(), cache.misses()), (0, 6));
+
+            let mut drained: Vec<_> = cache.drain().map(|(k, v)| (k, *v)).collect();
+            drained.sort();
+            assert_eq!(drained, [(0, 0), (1, 10)]);
+            assert_eq!(cache.size(), 0);
+            assert_eq!(cache.history().count(), 0);
+            assert!(!cache.is_tracked(&3));
+        }
+
+        let shared = DynamicCache::new(8);
+        for key in ["a", "b", "a"] {
+            shared.get_or_insert(key, || key.len());
+        }
+        assert_eq!(shared.keys(), ["a"]);
+        assert_eq!(shared.history(), ["a", "b", "a"]);
+        let entries = shared.entries();
+        assert_eq!(entries.len(), 1);
+        assert_eq!((entries[0].0.as_str(), *entries[0].1), ("a", 1));
+        assert_eq!(shared.drain().len(), 1);
+        assert!(shared.entries().is_empty());
+        assert!(shared.history().is_empty());
+    }
+
+    #[test]
+    fn entry_test() {
+        with_each_pointer! {
+            let mut cache = Local::new(8);
+            match cache.entry(0) {
+                Entry::Vacant(e) => {
+                    assert_eq!(e.key(), &0);
+                    assert_eq!(*e.insert(String::from("a")), "a");
+                }
+                _ => panic!("First request should be vacant"),
+            }
+            assert_eq!(cache.size(), 0);
+            match cache.entry(0) {
+                Entry::Tracked(e) => {
+                    assert_eq!(e.recent_request_count(), 2);
+                    assert_eq!(*e.insert(String::from("a")), "a");
+                }
+                _ => panic!("Second request should be tracked"),
+            }
+            assert_eq!(cache.size(), 1);
+            match cache.entry(0) {
+                Entry::Occupied(mut e) => {
+                    assert_eq!(e.get().as_str(), "a");
+                    assert_eq!(*e.insert(String::from("b")), "a");
+                    assert_eq!(e.get().as_str(), "b");
+                }
+                _ => panic!("Third request should be occupied"),
+            }
+            assert_eq!((cache.hits(), cache.misses()), (1, 2));
+
+            let v = cache
+                .entry(0)
+                .and_modify(|v| Ptr::make_mut(v).push('c'))
+                .or_insert_with(|| unreachable!());
+            assert_eq!(v.as_str(), "bc");
+            assert_eq!(cache.peek(&0).as_deref().map(String::as_str), Some("bc"));
+
+            if let Entry::Occupied(e) = cache.entry(0) {
+                assert_eq!(e.remove().as_str(), "bc");
+            }
+            assert_eq!(cache.size(), 0);
+            assert!(matches!(cache.entry(0), Entry::Tracked(_)));
+            assert_eq!(cache.entry(0).or_insert(String::from("d")).as_str(), "d");
+            assert_eq!(cache.size(), 1);
+
+            // Admission follows the threshold, and modifications follow the weight limits
+            cache.set_admit_threshold(1);
+            cache.entry(1).or_insert_with(|| String::from("e"));
+            assert!(cache.contains(&1));
+            cache.set_weigher(|_, v| v.len());
+            cache.set_max_weight(3);
+            cache
+                .entry(1)
+                .and_modify(|v| *v = Ptr::new(String::from("fghi")));
+            assert!(!cache.contains(&1));
+            assert_eq!(cache.weight(), 1);
+
+            // Counts leave out the key's own requests that were forgotten to make room
+            let mut short = Local::<u32, u32>::new(2);
+            for _ in 0..2 {
+                short.entry(9);
+            }
+            match short.entry(9) {
+                Entry::Tracked(e) => assert_eq!(e.recent_request_count(), 2),
+                _ => panic!("Repeated request should be tracked"),
+            }
+
+            // Leaving the value alone doesn't replace it
+            cache.reset_metrics();
+            let v = cache
+                .entry(0)
+                .and_modify(|_| ())
+                .or_insert_with(|| unreachable!());
+            assert!(Ptr::ptr_eq(&v, &cache.peek(&0).unwrap()));
+            assert_eq!(cache.stats().evictions.replaced, 0);
+        }
+    }
+
+    #[test]
+    fn eviction_test() {
+        use EvictionReason::*;
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let take = |log: &Mutex<Vec<_>>| std::mem::take(&mut *log.lock().unwrap());
+
+        with_each_pointer! {
+            let mut cache = Local::new(4);
+            let clock = ManualClock::new();
+            cache.set_clock(clock.clone());
+            cache.set_admit_threshold(1);
+            let l = log.clone();
+            cache.set_eviction_listener(move |k, v: Ptr<String>, reason| {
+                l.lock().unwrap().push((k, v.to_string(), reason))
+            });
+
+            cache.get(&0);
+            cache.insert(&0, String::from("a"));
+            cache.pop(&0);
+            assert_eq!(take(&log), [(0, String::from("a"), Removed)]);
+
+            cache.insert(&0, String::from("b"));
+            if let Entry::Occupied(mut e) = cache.entry(0) {
+                e.insert(String::from("c"));
+            }
+            assert_eq!(take(&log), [(0, String::from("b"), Replaced)]);
+
+            for i in 1..5 {
+                cache.get(&i);
+            }
+            assert_eq!(take(&log), [(0, String::from("c"), Forgotten)]);
+
+            cache.insert(&4, String::from("d"));
+            cache.insert(&3, String::from("e"));
+            cache.set_max_entries(1);
+            assert_eq!(take(&log), [(3, String::from("e"), Capacity)]);
+            cache.set_max_entries(8);
+
+            cache.insert(&2, String::from("f"));
+            cache.set_mem_len(2);
+            assert_eq!(take(&log), [(2, String::from("f"), Resized)]);
+
+            cache.insert_with_ttl(&3, String::from("g"), Duration::from_secs(1));
+            clock.advance(Duration::from_secs(2));
+            cache.get(&3);
+            assert_eq!(take(&log), [(3, String::from("g"), Expired)]);
+
+            cache.insert(&3, String::from("h"));
+            cache.clear_cache();
+            let mut cleared = take(&log);
+            cleared.sort_by_key(|(k, _, _)| *k);
+            assert_eq!(
+                cleared,
+                [
+                    (3, String::from("h"), Cleared),
+                    (4, String::from("d"), Cleared)
+                ]
+            );
+
+            // Drained values go to the caller instead
+            cache.get(&5);
+            cache.insert(&5, String::from("i"));
+            assert_eq!(cache.drain().count(), 1);
+            assert!(take(&log).is_empty());
+        }
+
+        // The shared cache calls the listener without holding any locks, so the listener can use
+        // the cache
+        let cache = DynamicCache::new(4);
+        cache.set_admit_threshold(1);
+        let (l, c) = (log.clone(), cache.clone());
+        cache.set_eviction_listener(move |k, v: Arc<String>, reason| {
+            assert!(c.peek(&k).is_none());
+            c.get_or_insert_dedup(&100, || String::from("z"));
+            l.lock().unwrap().push((k, v.to_string(), reason))
+        });
+        cache.get_or_insert(&0, || String::from("a"));
+        cache.pop(&0);
+        assert_eq!(take(&log), [(0, String::from("a"), Removed)]);
+        cache.get_or_insert_dedup(&1, || String::from("b"));
+        for i in 2..5 {
+            cache.get_or_insert_dedup(&i, || i.to_string());
+        }
+        assert!(take(&log).contains(&(1, String::from("b"), Forgotten)));
+    }
+
+    #[test]
+    fn sharded_test() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let cache = ShardedDynamicCache::new(1024, 4);
+        assert_eq!(cache.shard_count(), 4);
+        assert_eq!(cache.mem_len(), 1024);
+        assert_eq!(cache.max_weight(), usize::MAX);
+        assert_eq!(cache.max_entries(), usize::MAX);
+        assert_eq!(cache.ttl(), None);
+        assert_eq!(cache.admit_threshold(), 2);
+        assert_eq!(cache.mem_duration(), None);
+        for _ in 0..3 {
+            for i in 0..32 {
+                cache.get_or_insert(&i, || i.to_string());
+            }
+        }
+        assert_eq!(cache.size(), 32);
+        assert_eq!(cache.hits_misses(), (32, 64));
+        assert_eq!(cache.peek(&7).as_deref().map(String::as_str), Some("7"));
+        assert_eq!(cache.recent_request_count(&7), 3);
+        assert_eq!(cache.keys().len(), 32);
+
+        // Limits are split between the shards, rounding up
+        cache.set_max_entries(8);
+        assert!(cache.size() <= 8);
+        assert_eq!(cache.max_entries(), 8);
+        cache.set_max_entries(2);
+        assert_eq!(cache.max_entries(), 4);
+        assert!((1..=4).contains(&cache.size()));
+        for i in 0..32 {
+            cache.get_or_insert(&i, || i.to_string());
+        }
+        assert!((1..=4).contains(&cache.size()));
+
+        // The memory length is split the same way
+        cache.set_mem_len(10);
+        assert_eq!(cache.mem_len(), 12);
+        cache.set_mem_len(1024);
+
+        let evicted = Arc::new(AtomicUsize::new(0));
+        let e = evicted.clone();
+        cache.set_eviction_listener(move |_, _, reason| {
+            assert_eq!(reason, EvictionReason::Cleared);
+            e.fetch_add(1, Ordering::Relaxed);
+        });
+        let size = cache.size();
+        cache.clear_cache();
+        assert_eq!(evicted.load(Ordering::Relaxed), size);
+        assert_eq!(cache.size(), 0);
+
+        // Each key is only loaded once, no matter which thread gets to it first
+        cache.reset_metrics();
+        cache.set_max_entries(usize::MAX);
+        cache.set_admit_threshold(1);
+        let loads = Arc::new(AtomicUsize::new(0));
+        let threads: Vec<_> = (0..8)
+            .map(|t| {
+                let (cache, loads) = (cache.clone(), loads.clone());
+                std::thread::spawn(move || {
+                    for i in 0..100 {
+                        let key = (i + t * 13) % 100;
+                        let v = cache.get_or_insert_dedup(&key, || {
+                            loads.fetch_add(1, Ordering::Relaxed);
+                            key.to_string()
+                        });
+                        assert_eq!(*v, key.to_string());
+                    }
+                })
+            })
+            .collect();
+        threads.into_iter().for_each(|t| t.join().unwrap());
+        assert_eq!(loads.load(Ordering::Relaxed), 100);
+        assert_eq!(cache.size(), 100);
+        assert_eq!(cache.hits_misses().0 + cache.hits_misses().1, 800);
+    }
+
+    #[test]
+    fn buffered_test() {
+        let cache = BufferedDynamicCache::new(64);
+        assert_eq!(cache.get(&1), None);
+        assert_eq!(*cache.insert(&1, String::from("a")), "a");
+        assert!(!cache.contains(&1));
+        assert_eq!(cache.get_or_insert(&1, || String::from("a")).as_str(), "a");
+        assert!(cache.contains(&1));
+        assert_eq!(cache.get(&1).as_deref().map(String::as_str), Some("a"));
+        assert_eq!(cache.recent_request_count(&1), 3);
+        assert_eq!(cache.hits_misses(), (1, 2));
+        assert_eq!(cache.pop(&1).as_deref().map(String::as_str), Some("a"));
+        assert_eq!(cache.peek(&1), None);
+        assert_eq!(cache.size(), 0);
+
+        // Buffered hits count as requests once applied, keeping their values from being forgotten
+        cache.set_mem_len(4);
+        cache.get_or_insert(&2, || String::from("b"));
+        cache.get_or_insert(&2, || String::from("b"));
+        for _ in 0..3 {
+            assert!(cache.get(&2).is_some());
+        }
+        cache.get(&3);
+        assert!(cache.contains(&2));
+        let before = cache.stats();
+        for _ in 0..3 {
+            cache.get(&2);
+        }
+        let after = cache.stats();
+        assert_eq!(after.hits - before.hits, 3);
+        assert_eq!(after.misses, before.misses);
+        assert_eq!(cache.recent_request_count(&2), 3);
+        cache.clear_cache();
+        assert!(!cache.contains(&2));
+
+        // A hit replayed after its value has left counts, but doesn't bring the key back
+        let mut local: DynamicCacheLocal<i32, String> = DynamicCacheLocal::new(4);
+        local.replay_hit(5);
+        assert_eq!(local.hits(), 1);
+        assert!(!local.is_tracked(&5));
+    }
+
+    #[test]
+    fn buffered_admission_test() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        // Keys requested once are never admitted, and keys requested repeatedly always are, no
+        // matter how the requests from different threads interleave
+        let cache = BufferedDynamicCache::new(1 << 16);
+        let threads: Vec<_> = (0..8)
+            .map(|t| {
+                let cache = cache.clone();
+                std::thread::spawn(move || {
+                    for i in 0..500 {
+                        let unique = 1000 * (t + 1) + i;
+                        cache.get_or_insert(&unique, || unique);
+                        let shared = i % 50;
+                        assert_eq!(*cache.get_or_insert(&shared, || shared), shared);
+                    }
+                })
+            })
+            .collect();
+        threads.into_iter().for_each(|t| t.join().unwrap());
+        let mut stored: Vec<_> = cache.entries().into_iter().map(|(k, _)| k).collect();
+        stored.sort_unstable();
+        assert_eq!(stored, (0..50).collect::<Vec<_>>());
+        assert_eq!(cache.size(), 50);
+        let (hits, misses) = cache.hits_misses();
+        assert_eq!(hits + misses, 8000);
+
+        // With a short memory, values are constantly evicted while other threads are hitting
+        // them, and the lock-free view of stored values has to keep up
+        let cache = BufferedDynamicCache::new(32);
+        let loads = Arc::new(AtomicUsize::new(0));
+        let threads: Vec<_> = (0..8u64)
+            .map(|t| {
+                let (cache, loads) = (cache.clone(), loads.clone());
+                std::thread::spawn(move || {
+                    let mut rng = rand::rngs::StdRng::seed_from_u64(t);
+                    for _ in 0..2000 {
+                        let key = rng.gen_range(0, 24u64);
+                        let v = cache.get_or_insert(&key, || {
+                            loads.fetch_add(1, Ordering::Relaxed);
+                            key
+                        });
+                        assert_eq!(*v, key);
+                    }
+                })
+            })
+            .collect();
+        threads.into_iter().for_each(|t| t.join().unwrap());
+        cache.flush();
+        let entries = cache.entries();
+        assert_eq!(entries.len(), cache.size());
+        for (k, v) in entries {
+            assert_eq!(*v, k);
+            assert!(cache.recent_request_count(&k) > 0);
+        }
+        let (hits, misses) = cache.hits_misses();
+        assert_eq!(hits + misses, 16000);
+        assert_eq!(misses as usize, loads.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn stats_test() {
+        with_each_pointer! {
+            let mut cache = Local::new(4);
+            assert_eq!(cache.stats(), CacheStats::default());
+            assert_eq!(cache.stats().hit_ratio(), 0.0);
+            cache.insert(&0, 0);
+            cache.get(&1);
+            cache.insert(&1, 1);
+            cache.get(&1);
+            cache.insert(&1, 1);
+            cache.get(&1);
+            cache.pop(&1);
+            cache.pop(&2);
+            let before = cache.stats();
+            assert_eq!(
+                before,
+                CacheStats {
+                    hits: 1,
+                    misses: 2,
+                    admissions: 1,
+                    rejections: 2,
+                    pops: 2,
+                    resurrections: 0,
+                    evictions: EvictionCounts {
+                        removed: 1,
+                        ..Default::default()
+                    },
+                    history_len: 3,
+                    tracked: 1,
+                    stored: 0,
+                }
+            );
+            assert!((before.hit_ratio() - 1.0 / 3.0).abs() < f64::EPSILON);
+
+            // Differences only cover what happened in between, but keep the current state
+            cache.insert(&1, 1);
+            cache.set_max_entries(0);
+            for i in 2..6 {
+                cache.get(&i);
+            }
+            let diff = cache.stats() - before;
+            assert_eq!((diff.hits, diff.misses, diff.admissions), (0, 4, 1));
+            assert_eq!(diff.evictions.get(EvictionReason::Capacity), 1);
+            assert_eq!(diff.evictions.total(), 1);
+            assert_eq!((diff.history_len, diff.tracked, diff.stored), (4, 4, 0));
+
+            cache.reset_metrics();
+            let stats = cache.stats();
+            assert_eq!(stats.misses + stats.evictions.total(), 0);
+            assert_eq!(stats.history_len, 4);
+        }
+
+        // Sharded caches add up the stats of every shard
+        let cache = ShardedDynamicCache::new(256, 4);
+        for _ in 0..2 {
+            for i in 0..20 {
+                cache.get_or_insert(&i, || i);
+            }
+        }
+        cache.clear_cache();
+        let stats = cache.stats();
+        assert_eq!(
+            (stats.misses, stats.admissions, stats.rejections),
+            (40, 20, 20)
         );
+        assert_eq!(stats.evictions.cleared, 20);
+        assert_eq!(stats.tracked, 0);
+    }
+
+    #[test]
+    fn fingerprint_test() {
+        /// A key that can't be cloned.
+        #[derive(Debug, PartialEq, Eq, Hash)]
+        struct Path(String);
+
+        let mut cache = FingerprintCacheLocal::new(4);
+        let path = |s: &str| Path(String::from(s));
+        assert_eq!(cache.get_or_insert(path("a"), || 1), Arc::new(1));
+        assert!(!cache.contains(&path("a")));
+        assert_eq!(cache.get_or_insert(path("a"), || 1), Arc::new(1));
+        assert!(cache.contains(&path("a")));
+        assert_eq!(cache.get(&path("a")), Some(Arc::new(1)));
+        assert_eq!((cache.hits(), cache.misses(), cache.size()), (1, 2, 1));
+        assert_eq!(cache.iter().next().map(|(k, _)| k), Some(&path("a")));
+        assert_eq!(cache.pop(&path("a")), Some(Arc::new(1)));
+        assert_eq!(cache.recent_request_count(&path("a")), 3);
+        for s in ["b", "c", "d", "e"] {
+            cache.get(&path(s));
+        }
+        assert_eq!(cache.recent_request_count(&path("a")), 0);
+
+        // Keys with the same fingerprint share their history, but never each other's values
+        #[derive(Default)]
+        struct Collide;
+        impl std::hash::Hasher for Collide {
+            fn finish(&self) -> u64 {
+                7
+            }
+            fn write(&mut self, _: &[u8]) {}
+        }
+        let mut cache: FingerprintCacheLocal<u32, u32, std::hash::BuildHasherDefault<Collide>> =
+            FingerprintCacheLocal::default();
+        cache.get(&1);
+        cache.get(&2);
+        assert_eq!(cache.recent_request_count(&3), 2);
+        cache.insert(2, 20);
+        assert_eq!(cache.peek(&2), Some(Arc::new(20)));
+        assert_eq!(cache.get(&1), None);
+        cache.insert(1, 10);
+        assert_eq!(cache.peek(&1), Some(Arc::new(10)));
+        assert_eq!(cache.peek(&2), None);
         assert_eq!(cache.size(), 1);
-        assert_eq!(cache.hits_misses(), (1, 2));
+    }
 
-        assert_eq!(cache.mem_len(), 8);
+    #[test]
+    fn sketch_test() {
+        with_each_pointer! {
+            let mut cache = Local::with_sketch(4);
+            // A key requested once isn't tracked, and its value isn't stored
+            assert_eq!(cache.get_or_insert(&1, || 10), Ptr::new(10));
+            assert!(!cache.is_tracked(&2));
+            assert_eq!(cache.recent_request_count(&1), 1);
+            assert!(!cache.contains(&1));
+            assert_eq!(cache.stats().tracked, 0);
+            // The second request admits it
+            assert_eq!(cache.get_or_insert(&1, || 10), Ptr::new(10));
+            assert!(cache.contains(&1));
+            assert_eq!(cache.recent_request_count(&1), 2);
+            assert_eq!(cache.get(&1), Some(Ptr::new(10)));
+            assert_eq!((cache.hits(), cache.misses()), (1, 2));
+            // Stored values are forgotten once their requests are far enough in the past
+            for k in 2..5 {
+                cache.get(&k);
+            }
+            assert!(cache.contains(&1));
+            cache.get(&5);
+            assert!(!cache.contains(&1));
+            assert_eq!(cache.stats().evictions.forgotten, 1);
+
+            // Requests from well over a memory length ago don't count towards admission
+            let hasher =
+                std::hash::BuildHasherDefault::<std::collections::hash_map::DefaultHasher>::default();
+            let mut cache = Local::<u32, u32, _>::with_sketch_and_hasher(64, hasher);
+            cache.get(&1000);
+            for k in 0..128 {
+                cache.get(&k);
+            }
+            cache.get_or_insert(&1000, || 0);
+            assert!(!cache.contains(&1000));
+        }
+
+        let cache = DynamicCache::with_sketch(4);
+        assert_eq!(cache.get_or_insert(&"a", || 1), Arc::new(1));
+        assert_eq!(cache.get_or_insert(&"a", || 2), Arc::new(2));
+        assert_eq!(cache.get(&"a"), Some(Arc::new(2)));
+        cache.clear_cache();
+        assert_eq!(cache.recent_request_count(&"a"), 0);
+    }
+
+    #[test]
+    fn auto_mem_len_test() {
+        with_each_pointer! {
+            // A loop over more keys than the memory holds only hits once the memory grows to fit it
+            let mut cache = Local::new(8);
+            cache.set_auto_mem_len(Some(8..=256));
+            assert_eq!(cache.auto_mem_len(), Some(8..=256));
+            for i in 0..2000 {
+                cache.get_or_insert(&(i % 40), || i);
+            }
+            let len = cache.mem_len();
+            assert!((41..=64).contains(&len), "mem_len {len}");
+            cache.reset_metrics();
+            for i in 0..2000 {
+                cache.get_or_insert(&(i % 40), || i);
+            }
+            assert_eq!(cache.mem_len(), len);
+            assert!(cache.stats().hit_ratio() > 0.99);
+
+            // A small set of hot keys doesn't need a long memory
+            let mut cache = Local::new(256);
+            cache.set_auto_mem_len(Some(8..=256));
+            for i in 0..5000 {
+                cache.get_or_insert(&(i % 4), || i);
+            }
+            assert_eq!(cache.mem_len(), 8);
+            assert_eq!(cache.size(), 4);
+            assert_eq!(cache.stats().evictions.resized, 0);
+
+            // A shrink that costs hits is undone, and not tried again
+            let mut cache = Local::new(64);
+            cache.set_auto_mem_len(Some(16..=64));
+            let mut lens = Vec::new();
+            for i in 0..4000 {
+                cache.get_or_insert(&(i % 40), || i);
+                lens.push(cache.mem_len());
+            }
+            assert!(lens.iter().min().unwrap() < &40);
+            assert!(lens[3000..]
+                .iter()
+                .all(|&len| len == cache.mem_len() && len > 40));
+        }
+
+        // The bounds are applied immediately, and tuning can be turned off
+        let cache = DynamicCache::<u32, u32>::new(1000);
+        cache.set_auto_mem_len(Some(10..=100));
+        assert_eq!(cache.mem_len(), 100);
+        cache.set_auto_mem_len(None);
+        assert_eq!(cache.auto_mem_len(), None);
+        let sharded = ShardedDynamicCache::<u32, u32>::new(1000, 4);
+        sharded.set_auto_mem_len(Some(40..=400));
+        assert_eq!(sharded.mem_len(), 400);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn snapshot_test() {
+        let mut cache = DynamicCacheLocal::new(6);
+        cache.set_admit_threshold(3);
+        for k in [1, 2, 1, 3, 1, 2] {
+            cache.get_or_insert(&k, || k * 10);
+        }
+        cache.set_ttl(Some(Duration::from_secs(3600)));
+        cache.pop(&1);
+        let plain = serde_json::to_string(&cache.snapshot()).unwrap();
+        let snapshot: CacheSnapshot<u32, u32> = serde_json::from_str(&plain).unwrap();
+        assert_eq!((snapshot.version(), snapshot.mem_len()), (1, 6));
+
+        let mut restored = DynamicCacheLocal::from_snapshot(snapshot.clone()).unwrap();
+        assert_eq!(restored.stats(), cache.stats());
+        assert_eq!(restored.admit_threshold(), 3);
+        assert_eq!(restored.ttl(), Some(Duration::from_secs(3600)));
+        assert!(restored.history().eq(cache.history()));
+        // Both caches make the same decisions from here on
+        for k in [2, 2, 3, 4, 1, 4, 4, 5] {
+            let a = cache.get_or_insert(&k, || k * 10);
+            let b = restored.get_or_insert(&k, || k * 10);
+            assert_eq!(a, b);
+            assert_eq!(cache.contains(&k), restored.contains(&k));
+        }
+        assert_eq!(restored.stats(), cache.stats());
+
+        let shared = DynamicCache::from_snapshot(snapshot.clone()).unwrap();
+        assert_eq!(shared.snapshot().size(), snapshot.size());
+
+        // Sketch caches keep their counts, as long as keys hash the same way
+        type Fixed = std::hash::BuildHasherDefault<std::collections::hash_map::DefaultHasher>;
+        let mut sketched =
+            DynamicCacheLocal::<u32, u32, _>::with_sketch_and_hasher(100, Fixed::default());
+        sketched.get(&7);
+        let json = serde_json::to_string(&sketched.snapshot()).unwrap();
+        let mut restored: DynamicCacheLocal<u32, u32, Fixed> =
+            DynamicCacheLocal::from_snapshot_with_hasher(
+                serde_json::from_str(&json).unwrap(),
+                Fixed::default(),
+            )
+            .unwrap();
+        assert_eq!(restored.recent_request_count(&7), 1);
+        assert_eq!(restored.get_or_insert(&7, || 1), Arc::new(1));
+        assert!(restored.contains(&7));
+        let snapshot: CacheSnapshot<u32, u32> = serde_json::from_str(&json).unwrap();
+        let restored = DynamicCacheLocal::from_snapshot(snapshot).unwrap();
+        assert_eq!(restored.recent_request_count(&7), 0);
+
+        // Snapshots from other versions, or that don't fit together, are rejected
+        let mut value: serde_json::Value = serde_json::from_str(&plain).unwrap();
+        value["version"] = 2.into();
+        let snapshot: CacheSnapshot<u32, u32> = serde_json::from_value(value.clone()).unwrap();
+        let err = DynamicCacheLocal::from_snapshot(snapshot).unwrap_err();
+        assert_eq!(err, SnapshotError::Version(2));
+        assert_eq!(
+            err.to_string(),
+            "cache snapshot has format version 2, expected 1"
+        );
+        value["version"] = 1.into();
+        value["history"][0][0] = 5.into();
+        let snapshot: CacheSnapshot<u32, u32> = serde_json::from_value(value).unwrap();
+        assert!(matches!(
+            DynamicCacheLocal::from_snapshot(snapshot),
+            Err(SnapshotError::Invalid(_))
+        ));
+
+        // As are requests for a key whose counters don't count up to the key's own
+        let mut cache = DynamicCacheLocal::<u32, u32>::new(4);
+        cache.get(&1);
+        cache.get(&1);
+        let mut value = serde_json::to_value(cache.snapshot()).unwrap();
+        value["history"][1][1] = value["history"][0][1].clone();
+        let snapshot: CacheSnapshot<u32, u32> = serde_json::from_value(value).unwrap();
+        assert_eq!(
+            DynamicCacheLocal::from_snapshot(snapshot).unwrap_err(),
+            SnapshotError::Invalid("key counters in the history are out of order")
+        );
+    }
+
+    #[test]
+    fn history_export_test() {
+        with_each_pointer! {
+            let mut cache = Local::new(8);
+            for k in [1, 2, 1, 3, 2, 1] {
+                cache.get(&k);
+            }
+            cache.insert(&1, 10);
+            let history = cache.export_history();
+            assert_eq!(history, vec![(3, 1), (2, 2), (1, 3)]);
+
+            // A fresh cache stores previously hot values on the first insert, and only those
+            let mut restarted = Local::new(8);
+            restarted.import_history(history.clone());
+            assert_eq!(restarted.history().count(), 6);
+            assert_eq!(restarted.export_history(), history);
+            assert_eq!((restarted.hits(), restarted.misses()), (0, 0));
+            restarted.insert(&1, 10);
+            restarted.insert(&2, 20);
+            restarted.insert(&3, 30);
+            assert!(restarted.contains(&1) && restarted.contains(&2));
+            assert!(!restarted.contains(&3));
+
+            // Counts are capped to the memory length, and the newest keys win
+            let mut small = Local::<u32, u32>::new(3);
+            small.import_history([(1, 100), (2, 2)]);
+            assert_eq!(small.export_history(), vec![(1, 1), (2, 2)]);
+
+            // Sketch caches count the imported requests too
+            let mut sketched = Local::with_sketch(64);
+            sketched.import_history([(1, 3)]);
+            assert_eq!(sketched.recent_request_count(&1), 3);
+            assert_eq!(sketched.insert(&1, 10), Ptr::new(10));
+            assert!(sketched.contains(&1));
+        }
+
+        // Sharded caches send each key to its own shard
+        let sharded = ShardedDynamicCache::<u32, u32>::new(64, 4);
+        sharded.import_history((0..10).map(|k| (k, 2)));
+        let mut exported = sharded.export_history();
+        exported.sort();
+        assert_eq!(exported, (0..10).map(|k| (k, 2)).collect::<Vec<_>>());
+        assert_eq!(sharded.insert(&5, 50), Arc::new(50));
+        assert!(sharded.contains(&5));
+    }
+
+    #[test]
+    fn rc_test() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        /// A value that can't be sent between threads.
+        #[derive(Debug, PartialEq)]
+        struct Node(Rc<RefCell<Vec<u32>>>);
+        let node = |n| Node(Rc::new(RefCell::new(vec![n])));
+
+        let mut cache = RcDynamicCacheLocal::new(8);
+        assert_eq!((cache.size(), cache.mem_len()), (0, 8));
+        assert!(cache.get(&0).is_none());
+        assert_eq!(cache.insert(&0, node(0)).as_ref(), &node(0));
+        assert_eq!(cache.size(), 0);
+        assert!(cache.is_tracked(&0));
+        assert!(cache.get(&0).is_none());
+        assert_eq!(cache.recent_request_count(&0), 2);
+        let stored = cache.insert(&0, node(0));
+        assert_eq!(cache.size(), 1);
+        let hit = cache.get(&0).unwrap();
+        assert!(Rc::ptr_eq(&stored, &hit));
+        hit.0.borrow_mut().push(1);
+        assert_eq!(
+            cache.peek(&0),
+            Some(Rc::new(Node(Rc::new(RefCell::new(vec![0, 1])))))
+        );
+        assert_eq!((cache.hits(), cache.misses()), (1, 2));
+
+        // Same admission and forgetting rules as the `Arc` version
+        cache.set_admit_threshold(3);
+        assert_eq!(cache.admit_threshold(), 3);
+        for _ in 0..2 {
+            assert!(cache.get(&1).is_none());
+            cache.insert(&1, node(1));
+        }
+        assert!(!cache.contains(&1));
+        assert_eq!(cache.get_or_insert(&1, || node(1)).as_ref(), &node(1));
+        assert!(cache.contains(&1));
+        assert_eq!(cache.get_or_try_insert(&2, || Err("failed")), Err("failed"));
+        assert_eq!(cache.history().take(2).collect::<Vec<_>>(), [&2, &1]);
+        let mut keys: Vec<_> = cache.keys().copied().collect();
+        keys.sort();
+        assert_eq!(keys, [0, 1]);
+        cache.set_mem_len(3);
+        assert!(!cache.contains(&0));
+        assert!(cache.contains(&1));
+        assert_eq!(cache.pop(&1), Some(Rc::new(node(1))));
+        assert_eq!(cache.iter().count(), 0);
+        cache.clear_cache();
+        cache.reset_metrics();
+        assert!(!cache.is_tracked(&2));
+        assert_eq!((cache.hits(), cache.misses()), (0, 0));
+
+        let mut borrowed: RcDynamicCacheLocal<String, u32> = RcDynamicCacheLocal::default();
+        assert_eq!(borrowed.mem_len(), DEFAULT_MEM_LEN);
+        assert_eq!(borrowed.get_or_insert("abc", || 3).as_ref(), &3);
+        assert_eq!(borrowed.get_or_insert("abc", || 4).as_ref(), &4);
+        assert_eq!(borrowed.get("abc").as_deref(), Some(&4));
+    }
+
+    #[test]
+    fn weak_values_test() {
+        with_each_pointer! {
+            let mut cache = Local::new(4);
+            assert!(!cache.weak_values());
+            cache.set_weak_values(true);
+            assert!(cache.weak_values());
+            for k in [1, 2] {
+                cache.get_or_insert(&k, || k * 10);
+                cache.get_or_insert(&k, || k * 10);
+            }
+            // Only the value still held elsewhere comes back once forgotten
+            let held = cache.get(&1).unwrap();
+            for k in 3..7 {
+                cache.get(&k);
+            }
+            assert_eq!(cache.size(), 0);
+            assert_eq!(cache.stats().evictions.forgotten, 2);
+            let back = cache.get(&1).unwrap();
+            assert!(Ptr::ptr_eq(&held, &back));
+            assert!(cache.contains(&1));
+            assert!(cache.get(&2).is_none());
+            let stats = cache.stats();
+            assert_eq!((stats.hits, stats.resurrections), (2, 1));
+
+            // Values dropped for capacity come back too, but popped ones don't
+            cache.set_max_entries(1);
+            cache.get_or_insert(&7, || 70);
+            cache.get_or_insert(&7, || 70);
+            assert!(!cache.contains(&1));
+            assert_eq!(cache.get(&1).as_deref(), Some(&10));
+            assert!(!cache.contains(&7));
+            let popped = cache.pop(&1).unwrap();
+            assert!(cache.get(&1).is_none());
+            drop(popped);
+            assert_eq!(cache.stats().resurrections, 2);
+            cache.reset_metrics();
+            assert_eq!(cache.stats().resurrections, 0);
+
+            // Turning it off forgets everything kept so far
+            let held = cache.get_or_insert(&7, || 70);
+            cache.get_or_insert(&8, || 80);
+            cache.get_or_insert(&8, || 80);
+            assert!(!cache.contains(&7));
+            cache.set_weak_values(false);
+            assert!(cache.get(&7).is_none());
+            drop(held);
+        }
+
+        // Weak references are hashed with the cache's own hash builder
+        type Hasher = std::hash::BuildHasherDefault<std::collections::hash_map::DefaultHasher>;
+        let shared = DynamicCache::with_hasher(2, Hasher::default());
+        shared.set_weak_values(true);
+        let held = shared.get_or_insert(&"a", || 1);
+        let held = shared.get_or_insert(&"a", || held.as_ref() + 1);
+        shared.get(&"b");
+        shared.get(&"c");
+        assert!(!shared.contains(&"a"));
+        assert!(Arc::ptr_eq(&shared.get(&"a").unwrap(), &held));
+        assert_eq!(shared.stats().resurrections, 1);
+    }
+
+    #[test]
+    fn pin_test() {
+        with_each_pointer! {
+            let mut cache = Local::new(4);
+            // Pinned values skip admission and outlive their requests
+            let base = cache.pin(0, 100);
+            assert_eq!(*base, 100);
+            assert!(cache.is_pinned(&0));
+            assert!(!cache.is_tracked(&0));
+            assert_eq!(cache.get(&0).as_deref(), Some(&100));
+            for k in 1..10 {
+                cache.get_or_insert(&k, || k * 10);
+            }
+            assert_eq!(cache.peek(&0).as_deref(), Some(&100));
+            let stats = cache.stats();
+            assert_eq!((stats.tracked, stats.stored), (4, 1));
+            cache.set_mem_len(2);
+            assert!(cache.contains(&0));
+
+            // They count towards the limits, but aren't dropped to meet them
+            cache.set_max_entries(1);
+            cache.get_or_insert(&1, || 10);
+            cache.get_or_insert(&1, || 10);
+            assert!(cache.contains(&0));
+            assert!(!cache.contains(&1));
+            assert_eq!(cache.size(), 1);
+
+            // Once unpinned, they're forgotten like anything else
+            assert!(cache.unpin(&0));
+            assert!(!cache.unpin(&0));
+            assert!(!cache.contains(&0));
+            assert_eq!(cache.stats().evictions.forgotten, 1);
+            cache.pin(0, 100);
+            cache.get(&0);
+            assert!(cache.unpin(&0));
+            assert!(cache.contains(&0));
+            cache.get(&1);
+            cache.get(&1);
+            assert!(!cache.contains(&0));
+
+            // Only clearing the whole cache removes them
+            cache.set_max_entries(usize::MAX);
+            cache.pin(0, 100);
+            cache.get_or_insert(&1, || 10);
+            assert!(cache.contains(&1));
+            cache.clear_unpinned();
+            assert!(cache.contains(&0));
+            assert!(!cache.contains(&1));
+            assert_eq!(cache.stats().tracked, 0);
+            cache.clear_cache();
+            assert!(!cache.contains(&0));
+            assert_eq!(cache.size(), 0);
+        }
+
+        #[cfg(feature = "serde")]
+        {
+            let mut cache = DynamicCacheLocal::new(4);
+            cache.pin(0, 100);
+            let restored = DynamicCacheLocal::from_snapshot(cache.snapshot()).unwrap();
+            assert!(restored.is_pinned(&0));
+            assert_eq!(restored.peek(&0).as_deref(), Some(&100));
+        }
+
+        let shared = DynamicCache::new(2);
+        shared.pin("base", 1);
+        shared.get(&"a");
+        shared.get(&"b");
+        shared.get(&"c");
+        assert!(shared.is_pinned(&"base"));
+        assert_eq!(shared.get(&"base").as_deref(), Some(&1));
+        shared.clear_unpinned();
+        assert!(shared.contains(&"base"));
+        assert!(shared.unpin(&"base"));
+        assert!(!shared.contains(&"base"));
     }
 
     #[test]
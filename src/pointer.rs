@@ -0,0 +1,93 @@
+//! The reference-counted pointers a cache can hand out its values in.
+
+use std::ops::Deref;
+use std::rc::{self, Rc};
+use std::sync::{self, Arc};
+
+/// A pointer that a cache stores its values in, and hands out clones of: [`Arc`] for
+/// [`DynamicCacheLocal`](crate::DynamicCacheLocal), and [`Rc`] for
+/// [`RcDynamicCacheLocal`](crate::RcDynamicCacheLocal).
+///
+/// This trait is sealed, and can't be implemented outside of this crate.
+pub trait SharedPtr<V>: Clone + Deref<Target = V> + private::Sealed<V> {}
+
+impl<V> SharedPtr<V> for Arc<V> {}
+
+impl<V> SharedPtr<V> for Rc<V> {}
+
+mod private {
+    /// Everything the cache does with its pointers, kept out of the public API.
+    pub trait Sealed<V>: Sized {
+        /// A weak reference to a pointed-to value.
+        type Weak;
+
+        fn new(value: V) -> Self;
+
+        fn ptr_eq(this: &Self, other: &Self) -> bool;
+
+        fn strong_count(this: &Self) -> usize;
+
+        fn downgrade(this: &Self) -> Self::Weak;
+
+        fn upgrade(weak: &Self::Weak) -> Option<Self>;
+
+        /// Check if a weakly referenced value is still alive.
+        fn is_alive(weak: &Self::Weak) -> bool;
+    }
+}
+
+impl<V> private::Sealed<V> for Arc<V> {
+    type Weak = sync::Weak<V>;
+
+    fn new(value: V) -> Self {
+        Arc::new(value)
+    }
+
+    fn ptr_eq(this: &Self, other: &Self) -> bool {
+        Arc::ptr_eq(this, other)
+    }
+
+    fn strong_count(this: &Self) -> usize {
+        Arc::strong_count(this)
+    }
+
+    fn downgrade(this: &Self) -> Self::Weak {
+        Arc::downgrade(this)
+    }
+
+    fn upgrade(weak: &Self::Weak) -> Option<Self> {
+        weak.upgrade()
+    }
+
+    fn is_alive(weak: &Self::Weak) -> bool {
+        weak.strong_count() > 0
+    }
+}
+
+impl<V> private::Sealed<V> for Rc<V> {
+    type Weak = rc::Weak<V>;
+
+    fn new(value: V) -> Self {
+        Rc::new(value)
+    }
+
+    fn ptr_eq(this: &Self, other: &Self) -> bool {
+        Rc::ptr_eq(this, other)
+    }
+
+    fn strong_count(this: &Self) -> usize {
+        Rc::strong_count(this)
+    }
+
+    fn downgrade(this: &Self) -> Self::Weak {
+        Rc::downgrade(this)
+    }
+
+    fn upgrade(weak: &Self::Weak) -> Option<Self> {
+        weak.upgrade()
+    }
+
+    fn is_alive(weak: &Self::Weak) -> bool {
+        weak.strong_count() > 0
+    }
+}
@@ -0,0 +1,90 @@
+//! A single-threaded cache that hands out `Rc` pointers.
+
+use crate::{DynamicCacheLocal, DEFAULT_MEM_LEN};
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hash};
+use std::ops::{Deref, DerefMut};
+use std::rc::Rc;
+
+/// A cache that works like [`DynamicCacheLocal`], except that it stores and returns [`Rc<V>`]
+/// instead of `Arc<V>`.
+///
+/// Values are counted without atomic operations, and don't need to be [`Send`] or [`Sync`], so
+/// they can hold `Rc`s or `RefCell`s of their own, like a parse tree full of shared nodes. The
+/// cache itself can't be sent to another thread either.
+///
+/// It dereferences to a `DynamicCacheLocal` that hands out `Rc<V>` wherever the usual one hands
+/// out an `Arc<V>`, so requests are remembered and values admitted exactly the same way, and
+/// every method other than the constructors and snapshots is available.
+pub struct RcDynamicCacheLocal<K, V, S = RandomState>(DynamicCacheLocal<K, V, S, Rc<V>>);
+
+impl<K: Clone + Eq + Hash, V> RcDynamicCacheLocal<K, V> {
+    /// Create and initialize a new cache.
+    pub fn new(mem_len: usize) -> Self {
+        Self::with_hasher(mem_len, RandomState::new())
+    }
+
+    /// Create and initialize a new cache, with space for at least `capacity` tracked keys
+    /// allocated up front.
+    pub fn with_capacity(mem_len: usize, capacity: usize) -> Self {
+        Self::with_capacity_and_hasher(mem_len, capacity, RandomState::new())
+    }
+
+    /// Create and initialize a new cache that counts requests with a compact sketch, instead of
+    /// remembering each one. See [`DynamicCacheLocal::with_sketch_and_hasher`].
+    pub fn with_sketch(mem_len: usize) -> Self {
+        Self::with_sketch_and_hasher(mem_len, RandomState::new())
+    }
+}
+
+impl<K: Clone + Eq + Hash, V, S: BuildHasher> RcDynamicCacheLocal<K, V, S> {
+    /// Create and initialize a new cache, using the given hash builder to hash keys. The same
+    /// warnings given for [`HashMap::with_hasher`](std::collections::HashMap::with_hasher) apply
+    /// here.
+    pub fn with_hasher(mem_len: usize, hash_builder: S) -> Self {
+        Self::with_capacity_and_hasher(mem_len, 0, hash_builder)
+    }
+
+    /// Create and initialize a new cache with space for at least `capacity` tracked keys, using
+    /// the given hash builder to hash keys. The same warnings given for
+    /// [`HashMap::with_capacity_and_hasher`](std::collections::HashMap::with_capacity_and_hasher)
+    /// apply here.
+    pub fn with_capacity_and_hasher(mem_len: usize, capacity: usize, hash_builder: S) -> Self {
+        Self(DynamicCacheLocal::create(mem_len, capacity, hash_builder))
+    }
+
+    /// Create and initialize a new cache that counts requests with a compact sketch, instead of
+    /// remembering each one, using the given hash builder to hash keys. See
+    /// [`DynamicCacheLocal::with_sketch_and_hasher`].
+    pub fn with_sketch_and_hasher(mem_len: usize, hash_builder: S) -> Self {
+        Self(DynamicCacheLocal::create_with_sketch(mem_len, hash_builder))
+    }
+}
+
+impl<K, V, S> Deref for RcDynamicCacheLocal<K, V, S> {
+    type Target = DynamicCacheLocal<K, V, S, Rc<V>>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<K, V, S> DerefMut for RcDynamicCacheLocal<K, V, S> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+impl<K: Clone + Eq + Hash, V, S: BuildHasher + Default> Default for RcDynamicCacheLocal<K, V, S> {
+    /// Create an empty cache with a memory length of [`DEFAULT_MEM_LEN`].
+    fn default() -> Self {
+        Self::with_hasher(DEFAULT_MEM_LEN, S::default())
+    }
+}
+
+impl<K: fmt::Debug, V: fmt::Debug, S> fmt::Debug for RcDynamicCacheLocal<K, V, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("RcDynamicCacheLocal").field(&self.0).finish()
+    }
+}
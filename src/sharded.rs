@@ -0,0 +1,487 @@
+//! A cache split into independently locked shards.
+
+use crate::{CacheStats, Clock, DynamicCache, EvictionReason};
+use std::borrow::Borrow;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash};
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// A [`DynamicCache`] split into several independent shards, each with its own lock, so that
+/// threads working with different keys rarely wait on each other.
+///
+/// Keys are assigned to shards by their hash, and each shard only remembers requests for its own
+/// keys, using an equal share of the total memory length. Admission and eviction work exactly as
+/// in [`DynamicCache`], but separately within each shard, so a shard receiving more than its share
+/// of requests forgets them sooner than a single cache of the same total length would. Limits on
+/// weight and entry count are likewise split evenly between the shards, rounding up, so the
+/// shards can together go over a limit by less than one share each.
+///
+/// Metrics, [`size`](Self::size), and [`weight`](Self::weight) are totals across all shards.
+#[derive(Clone, Debug)]
+pub struct ShardedDynamicCache<K, V, S = RandomState> {
+    shards: Box<[DynamicCache<K, V, S>]>,
+    hash_builder: S,
+}
+
+impl<K: Clone + Eq + Hash, V> ShardedDynamicCache<K, V> {
+    /// Create and initialize a new cache with `shard_count` shards, sharing a total memory length
+    /// of `mem_len` between them.
+    pub fn new(mem_len: usize, shard_count: usize) -> Self {
+        Self::with_hasher(mem_len, shard_count, RandomState::new())
+    }
+}
+
+impl<K: Clone + Eq + Hash, V, S: BuildHasher + Clone> ShardedDynamicCache<K, V, S> {
+    /// Create and initialize a new cache with `shard_count` shards, sharing a total memory length
+    /// of `mem_len` between them, and using the given hash builder both to pick the shard for a
+    /// key and to hash keys within each shard. The same warnings given for
+    /// [`HashMap::with_hasher`](std::collections::HashMap::with_hasher) apply here.
+    pub fn with_hasher(mem_len: usize, shard_count: usize, hash_builder: S) -> Self {
+        // Just make it work if an invalid value is thrown in
+        let shard_count = shard_count.max(1);
+        let shards = (0..shard_count)
+            .map(|_| DynamicCache::with_hasher(mem_len.div_ceil(shard_count), hash_builder.clone()))
+            .collect();
+        Self {
+            shards,
+            hash_builder,
+        }
+    }
+
+    /// Get the shard responsible for a key.
+    fn shard<Q>(&self, key: &Q) -> &DynamicCache<K, V, S>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash,
+    {
+        &self.shards[self.shard_index(key)]
+    }
+
+    /// Get the index of the shard that holds a key.
+    fn shard_index<Q>(&self, key: &Q) -> usize
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash,
+    {
+        let hash = self.hash_builder.hash_one(key);
+        // The shards' maps use the same hasher, taking bucket positions from the low bits and
+        // control bytes from the top 7, so pick the shard from the bits in between. Otherwise each
+        // shard's keys would all share their low bits, and crowd into a fraction of its buckets.
+        ((hash >> 32) % self.shards.len() as u64) as usize
+    }
+
+    /// Split a limit for the whole cache evenly between the shards, rounding up so that every
+    /// shard can store something under any nonzero limit.
+    fn split(&self, total: usize) -> usize {
+        if total == usize::MAX {
+            total
+        } else {
+            total.div_ceil(self.shards.len())
+        }
+    }
+
+    /// Add up a limit over every shard, where `usize::MAX` means unlimited.
+    fn total(&self, limit: impl Fn(&DynamicCache<K, V, S>) -> usize) -> usize {
+        self.shards
+            .iter()
+            .map(limit)
+            .fold(0, |total, limit| total.saturating_add(limit))
+    }
+
+    /// Get the number of shards in the cache.
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Attempt to retrieve a value from the cache. This updates the cache's memory of what values
+    /// have been requested.
+    ///
+    /// The key may be any borrowed form of the cache's key type, but [`Hash`] and [`Eq`] on the
+    /// borrowed form *must* match those for the key type.
+    pub fn get<Q>(&self, key: &Q) -> Option<Arc<V>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq + ToOwned<Owned = K>,
+    {
+        self.shard(key).get(key)
+    }
+
+    /// Attempt to remove a value from the cache. The cache's memory of requests for the key is
+    /// kept.
+    ///
+    /// The key may be any borrowed form of the cache's key type, but [`Hash`] and [`Eq`] on the
+    /// borrowed form *must* match those for the key type.
+    pub fn pop<Q>(&self, key: &Q) -> Option<Arc<V>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.shard(key).pop(key)
+    }
+
+    /// Insert a value into the cache, following the same rules as [`DynamicCache::insert`].
+    ///
+    /// The key may be any borrowed form of the cache's key type, but [`Hash`] and [`Eq`] on the
+    /// borrowed form *must* match those for the key type.
+    pub fn insert<Q>(&self, key: &Q, value: V) -> Arc<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.shard(key).insert(key, value)
+    }
+
+    /// Insert a value into the cache, exactly like [`insert`](Self::insert), except that a newly
+    /// stored value expires after `ttl` instead of the cache's default time-to-live.
+    ///
+    /// The key may be any borrowed form of the cache's key type, but [`Hash`] and [`Eq`] on the
+    /// borrowed form *must* match those for the key type.
+    pub fn insert_with_ttl<Q>(&self, key: &Q, value: V, ttl: Duration) -> Arc<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.shard(key).insert_with_ttl(key, value, ttl)
+    }
+
+    /// Fetch an item via the cache, potentially filling in the cache on a miss via the function
+    /// `f`. The shard is unlocked while calling the function, so `f` may be called more than once
+    /// with the same parameters if there are several threads using the cache.
+    ///
+    /// The key may be any borrowed form of the cache's key type, but [`Hash`] and [`Eq`] on the
+    /// borrowed form *must* match those for the key type.
+    pub fn get_or_insert<Q, F>(&self, key: &Q, f: F) -> Arc<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq + ToOwned<Owned = K>,
+        F: FnOnce() -> V,
+    {
+        self.shard(key).get_or_insert(key, f)
+    }
+
+    /// Fetch an item via the cache, potentially filling in the cache on a miss via the function
+    /// `f`. Concurrent requests for the same key share a single call to a loading function, as
+    /// with [`DynamicCache::get_or_insert_dedup`].
+    ///
+    /// The key may be any borrowed form of the cache's key type, but [`Hash`] and [`Eq`] on the
+    /// borrowed form *must* match those for the key type.
+    pub fn get_or_insert_dedup<Q, F>(&self, key: &Q, f: F) -> Arc<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq + ToOwned<Owned = K>,
+        F: FnOnce() -> V,
+    {
+        self.shard(key).get_or_insert_dedup(key, f)
+    }
+
+    /// Fetch an item via the cache, potentially filling in the cache on a miss by awaiting the
+    /// future returned by `f`. Concurrent requests for the same key share a single load, as with
+    /// [`DynamicCache::get_or_insert_async`].
+    ///
+    /// The key may be any borrowed form of the cache's key type, but [`Hash`] and [`Eq`] on the
+    /// borrowed form *must* match those for the key type.
+    #[cfg(feature = "async")]
+    pub async fn get_or_insert_async<Q, F, Fut>(&self, key: &Q, f: F) -> Arc<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq + ToOwned<Owned = K>,
+        F: FnOnce() -> Fut,
+        Fut: std::future::Future<Output = V>,
+    {
+        self.shard(key).get_or_insert_async(key, f).await
+    }
+
+    /// Fetch an item via the cache, potentially filling in the cache on a miss via the fallible
+    /// function `f`. If `f` fails, the error is returned and nothing is stored.
+    ///
+    /// The key may be any borrowed form of the cache's key type, but [`Hash`] and [`Eq`] on the
+    /// borrowed form *must* match those for the key type.
+    pub fn get_or_try_insert<Q, E, F>(&self, key: &Q, f: F) -> Result<Arc<V>, E>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq + ToOwned<Owned = K>,
+        F: FnOnce() -> Result<V, E>,
+    {
+        self.shard(key).get_or_try_insert(key, f)
+    }
+
+    /// Retrieve a value from the cache without updating the cache's memory of what values have
+    /// been requested, or its hit/miss metrics.
+    ///
+    /// The key may be any borrowed form of the cache's key type, but [`Hash`] and [`Eq`] on the
+    /// borrowed form *must* match those for the key type.
+    pub fn peek<Q>(&self, key: &Q) -> Option<Arc<V>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.shard(key).peek(key)
+    }
+
+    /// Check if a value is currently stored in the cache. This does not update the cache's memory
+    /// of what values have been requested.
+    ///
+    /// The key may be any borrowed form of the cache's key type, but [`Hash`] and [`Eq`] on the
+    /// borrowed form *must* match those for the key type.
+    pub fn contains<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.shard(key).contains(key)
+    }
+
+    /// Check if there are any requests for a key in the cache's recent request memory, whether or
+    /// not a value is stored for it. This does not update the cache's memory.
+    ///
+    /// The key may be any borrowed form of the cache's key type, but [`Hash`] and [`Eq`] on the
+    /// borrowed form *must* match those for the key type.
+    pub fn is_tracked<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.shard(key).is_tracked(key)
+    }
+
+    /// Get the number of requests for a key in the cache's recent request memory. This does not
+    /// update the cache's memory.
+    ///
+    /// The key may be any borrowed form of the cache's key type, but [`Hash`] and [`Eq`] on the
+    /// borrowed form *must* match those for the key type.
+    pub fn recent_request_count<Q>(&self, key: &Q) -> u32
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.shard(key).recent_request_count(key)
+    }
+
+    /// Get the number of items currently stored across all shards.
+    pub fn size(&self) -> usize {
+        self.shards.iter().map(DynamicCache::size).sum()
+    }
+
+    /// Get the total weight of all items currently stored across all shards.
+    pub fn weight(&self) -> usize {
+        self.shards.iter().map(DynamicCache::weight).sum()
+    }
+
+    /// Get the maximum total weight of stored items, adding up every shard's share. This is
+    /// `usize::MAX`, meaning unlimited, unless changed with
+    /// [`set_max_weight`](Self::set_max_weight), and can be a little over the weight that was set
+    /// there since the shares are rounded up.
+    pub fn max_weight(&self) -> usize {
+        self.total(DynamicCache::max_weight)
+    }
+
+    /// Change the maximum total weight of stored items. Each shard gets an equal share of it,
+    /// rounded up.
+    pub fn set_max_weight(&self, max_weight: usize) {
+        let max_weight = self.split(max_weight);
+        self.shards
+            .iter()
+            .for_each(|s| s.set_max_weight(max_weight));
+    }
+
+    /// Set the function used to weigh stored items, replacing the default weight of 1 per item.
+    pub fn set_weigher<F>(&self, weigher: F)
+    where
+        F: Fn(&K, &V) -> usize + Send + Sync + 'static,
+    {
+        let weigher = Arc::new(weigher);
+        for shard in self.shards.iter() {
+            let weigher = weigher.clone();
+            shard.set_weigher(move |k, v| weigher(k, v));
+        }
+    }
+
+    /// Get the maximum number of items that can be stored in the cache at once, adding up every
+    /// shard's share. This is `usize::MAX`, meaning only limited by the memory length, unless
+    /// changed with [`set_max_entries`](Self::set_max_entries), and can be a little over the
+    /// count that was set there since the shares are rounded up.
+    pub fn max_entries(&self) -> usize {
+        self.total(DynamicCache::max_entries)
+    }
+
+    /// Change the maximum number of items that can be stored in the cache at once. Each shard
+    /// gets an equal share of it, rounded up, so with fewer entries than shards, each shard can
+    /// store one.
+    pub fn set_max_entries(&self, max_entries: usize) {
+        let max_entries = self.split(max_entries);
+        self.shards
+            .iter()
+            .for_each(|s| s.set_max_entries(max_entries));
+    }
+
+    /// Get the default time-to-live for newly stored values. This is `None`, meaning values don't
+    /// expire, unless changed with [`set_ttl`](Self::set_ttl).
+    pub fn ttl(&self) -> Option<Duration> {
+        self.shards[0].ttl()
+    }
+
+    /// Change the default time-to-live for newly stored values in every shard.
+    pub fn set_ttl(&self, ttl: Option<Duration>) {
+        self.shards.iter().for_each(|s| s.set_ttl(ttl));
+    }
+
+    /// Replace the clock used by every shard for time-based features.
+    pub fn set_clock<C: Clock + Clone + 'static>(&self, clock: C) {
+        self.shards.iter().for_each(|s| s.set_clock(clock.clone()));
+    }
+
+    /// Get the total length of the cache's recent request memory, across all shards.
+    pub fn mem_len(&self) -> usize {
+        self.shards.iter().map(DynamicCache::mem_len).sum()
+    }
+
+    /// Get the number of recent requests a key needs before its value is stored in the cache.
+    pub fn admit_threshold(&self) -> u32 {
+        self.shards[0].admit_threshold()
+    }
+
+    /// Change the number of recent requests a key needs before its value is stored in the cache.
+    pub fn set_admit_threshold(&self, threshold: u32) {
+        self.shards
+            .iter()
+            .for_each(|s| s.set_admit_threshold(threshold));
+    }
+
+    /// Change the total length of the cache's recent request memory. Each shard gets an equal
+    /// share of it, rounded up, so [`mem_len`](Self::mem_len) can be a little over the length
+    /// given here.
+    pub fn set_mem_len(&self, new_len: usize) {
+        let new_len = self.split(new_len);
+        self.shards.iter().for_each(|s| s.set_mem_len(new_len));
+    }
+
+    /// Check whether values that leave any of the shards are kept as weak references. See
+    /// [`DynamicCacheLocal::set_weak_values`](crate::DynamicCacheLocal::set_weak_values).
+    pub fn weak_values(&self) -> bool {
+        self.shards[0].weak_values()
+    }
+
+    /// Choose whether values that leave any of the shards are kept as weak references. See
+    /// [`DynamicCacheLocal::set_weak_values`](crate::DynamicCacheLocal::set_weak_values).
+    pub fn set_weak_values(&self, enabled: bool) {
+        self.shards.iter().for_each(|s| s.set_weak_values(enabled));
+    }
+
+    /// Get the bounds the total memory length is kept within, adding up every shard's bounds, if
+    /// it's [tuned automatically](Self::set_auto_mem_len).
+    pub fn auto_mem_len(&self) -> Option<RangeInclusive<usize>> {
+        let n = self.shards.len();
+        let bounds = self.shards[0].auto_mem_len()?;
+        Some(bounds.start().saturating_mul(n)..=bounds.end().saturating_mul(n))
+    }
+
+    /// Tune the memory length of every shard automatically, or stop tuning it with `None`. The
+    /// bounds are for the total memory length, so each shard gets an equal share of them. See
+    /// [`DynamicCacheLocal::set_auto_mem_len`](crate::DynamicCacheLocal::set_auto_mem_len).
+    pub fn set_auto_mem_len(&self, bounds: Option<RangeInclusive<usize>>) {
+        let bounds = bounds.map(|b| self.split(*b.start())..=self.split(*b.end()));
+        self.shards
+            .iter()
+            .for_each(|s| s.set_auto_mem_len(bounds.clone()));
+    }
+
+    /// Get how long requests are kept in the cache's recent request memory. This is `None`,
+    /// meaning requests are only forgotten once the memory is full, unless changed with
+    /// [`set_mem_duration`](Self::set_mem_duration).
+    pub fn mem_duration(&self) -> Option<Duration> {
+        self.shards[0].mem_duration()
+    }
+
+    /// Change how long requests are kept in the cache's recent request memory, in every shard.
+    pub fn set_mem_duration(&self, duration: Option<Duration>) {
+        self.shards
+            .iter()
+            .for_each(|s| s.set_mem_duration(duration));
+    }
+
+    /// Set a function to be called with every stored value that leaves any of the shards, along
+    /// with its key and the reason it was removed. As with [`DynamicCache::set_eviction_listener`],
+    /// it's only called while no shard is locked.
+    pub fn set_eviction_listener<F>(&self, listener: F)
+    where
+        F: Fn(K, Arc<V>, EvictionReason) + Send + Sync + 'static,
+    {
+        let listener = Arc::new(listener);
+        for shard in self.shards.iter() {
+            let listener = listener.clone();
+            shard.set_eviction_listener(move |k, v, reason| listener(k, v, reason));
+        }
+    }
+
+    /// Get a snapshot of all values stored in the cache, in arbitrary order. Each shard is locked
+    /// in turn, so the snapshot may mix states from different points in time.
+    pub fn entries(&self) -> Vec<(K, Arc<V>)> {
+        self.shards.iter().flat_map(DynamicCache::entries).collect()
+    }
+
+    /// Get a snapshot of the keys of all values stored in the cache, in arbitrary order.
+    pub fn keys(&self) -> Vec<K> {
+        self.shards.iter().flat_map(DynamicCache::keys).collect()
+    }
+
+    /// Get every key in the cache's recent request memory along with its recent request count,
+    /// one shard after another. See
+    /// [`DynamicCacheLocal::export_history`](crate::DynamicCacheLocal::export_history).
+    pub fn export_history(&self) -> Vec<(K, u32)> {
+        self.shards
+            .iter()
+            .flat_map(DynamicCache::export_history)
+            .collect()
+    }
+
+    /// Add requests to the cache's memory, as exported by
+    /// [`export_history`](Self::export_history). Each key's requests go to its own shard, in the
+    /// order given. See
+    /// [`DynamicCacheLocal::import_history`](crate::DynamicCacheLocal::import_history).
+    pub fn import_history<I>(&self, history: I)
+    where
+        I: IntoIterator<Item = (K, u32)>,
+    {
+        let mut per_shard = vec![Vec::new(); self.shards.len()];
+        for (key, count) in history {
+            per_shard[self.shard_index::<K>(&key)].push((key, count));
+        }
+        for (shard, history) in self.shards.iter().zip(per_shard) {
+            shard.import_history(history);
+        }
+    }
+
+    /// Clear out all memory in the cache, returning all values that were stored in it.
+    pub fn drain(&self) -> Vec<(K, Arc<V>)> {
+        self.shards.iter().flat_map(DynamicCache::drain).collect()
+    }
+
+    /// Clear out all stored values and all memory in the cache.
+    pub fn clear_cache(&self) {
+        self.shards.iter().for_each(DynamicCache::clear_cache);
+    }
+
+    /// Get the cache metrics, totalled across all shards, as a pair `(hits, misses)`.
+    pub fn hits_misses(&self) -> (u64, u64) {
+        self.shards
+            .iter()
+            .map(DynamicCache::hits_misses)
+            .fold((0, 0), |(h, m), (sh, sm)| (h + sh, m + sm))
+    }
+
+    /// Get a snapshot of the cache's statistics, totalled across all shards. Each shard is locked
+    /// in turn, so the snapshot may mix states from different points in time.
+    pub fn stats(&self) -> CacheStats {
+        self.shards
+            .iter()
+            .map(DynamicCache::stats)
+            .fold(CacheStats::default(), |total, stats| total + stats)
+    }
+
+    /// Reset all of the cache's metrics, including everything counted by [`stats`](Self::stats).
+    pub fn reset_metrics(&self) {
+        self.shards.iter().for_each(DynamicCache::reset_metrics);
+    }
+}
@@ -0,0 +1,159 @@
+//! A compact, approximate history of how often keys have been requested, used in place of
+//! tracking every requested key individually.
+
+use std::collections::VecDeque;
+
+/// Counter rows in the count-min sketch, each indexed with a different hash.
+const ROWS: usize = 4;
+
+/// The largest count a sketch counter can hold.
+pub(crate) const MAX_COUNT: u32 = 15;
+
+/// Spread a key hash into an independent-looking hash for each use, via splitmix64.
+fn mix(hash: u64, seed: u64) -> u64 {
+    let mut z = hash ^ seed.wrapping_mul(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+/// Request history for caches that only track keys worth caching.
+///
+/// Every request is counted in a count-min sketch of 4-bit counters, behind a "doorkeeper" bloom
+/// filter that absorbs the first request for each key, so keys only requested once never touch
+/// the counters. Requests are counted in periods of two thirds of the memory length. At the end
+/// of each period, every counter is halved and a new doorkeeper is started, while the previous
+/// one is kept for another period. A key's first request is therefore remembered for between two
+/// thirds and four thirds of a memory length, and a key requested twice within about a memory
+/// length is admitted, as it would be without a sketch.
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) struct SketchHistory {
+    /// `ROWS` rows of `width` 4-bit counters, 16 to a word.
+    counters: Box<[u64]>,
+    /// Bits set by keys requested in the current period.
+    doorkeeper: Box<[u64]>,
+    /// The doorkeeper from the previous period, which keys still count as seen in.
+    previous_doorkeeper: Box<[u64]>,
+    /// Number of counters per row, always a power of two.
+    width: usize,
+    /// Requests counted in the current period.
+    additions: usize,
+    /// The number of requests in each period.
+    period: usize,
+    /// The number of requests made so far, used as the position of the next request.
+    pub(crate) requests: u64,
+    /// The position of each request in the cache's memory queue, in the same order as the queue.
+    pub(crate) positions: VecDeque<u64>,
+}
+
+impl SketchHistory {
+    /// Create a sketch sized for a memory of `mem_len` requests.
+    pub(crate) fn new(mem_len: usize) -> Self {
+        let width = mem_len.next_power_of_two().clamp(64, 1 << 30);
+        Self {
+            counters: vec![0; ROWS * width / 16].into_boxed_slice(),
+            doorkeeper: vec![0; width / 8].into_boxed_slice(),
+            previous_doorkeeper: vec![0; width / 8].into_boxed_slice(),
+            width,
+            additions: 0,
+            // A request is remembered for one to two periods, so on average for a memory length
+            period: (mem_len / 3 * 2).max(1),
+            requests: 0,
+            positions: VecDeque::new(),
+        }
+    }
+
+    /// Check that the sketch's parts are sized consistently and its request positions are in
+    /// order, as they are unless it was restored from a modified snapshot.
+    #[cfg(feature = "serde")]
+    pub(crate) fn is_valid(&self) -> bool {
+        self.width.is_power_of_two()
+            && (64..=1 << 30).contains(&self.width)
+            && self.counters.len() == ROWS * self.width / 16
+            && self.doorkeeper.len() == self.width / 8
+            && self.previous_doorkeeper.len() == self.width / 8
+            && self.period > 0
+            // Far from overflowing, even with a full memory's length added
+            && self.requests <= u64::MAX / 2
+            && !matches!(self.positions.front(), Some(&at) if at > self.requests)
+            && self.positions.iter().zip(self.positions.iter().skip(1)).all(|(a, b)| a >= b)
+    }
+
+    /// Get the indexes of the doorkeeper bits for a key.
+    fn door_bits(&self, hash: u64) -> [usize; 2] {
+        let bits = self.doorkeeper.len() * 64;
+        let h = mix(hash, ROWS as u64);
+        [(h as usize) & (bits - 1), ((h >> 32) as usize) & (bits - 1)]
+    }
+
+    /// Check if the key with the given doorkeeper bits was requested in this period or the last.
+    fn seen(&self, bits: [usize; 2]) -> bool {
+        let all_set = |door: &[u64]| bits.iter().all(|&b| door[b / 64] & (1 << (b % 64)) != 0);
+        all_set(&self.doorkeeper) || all_set(&self.previous_doorkeeper)
+    }
+
+    /// Get the word index and bit shift of each row's counter for a key.
+    fn counter_slots(&self, hash: u64) -> [(usize, u32); ROWS] {
+        std::array::from_fn(|row| {
+            let index = row * self.width + (mix(hash, row as u64) as usize & (self.width - 1));
+            (index / 16, (index % 16) as u32 * 4)
+        })
+    }
+
+    /// Count a request for the key with the given hash.
+    pub(crate) fn record(&mut self, hash: u64) {
+        if self.additions >= self.period {
+            self.age();
+        }
+        let bits = self.door_bits(hash);
+        if self.seen(bits) {
+            for (word, shift) in self.counter_slots(hash) {
+                if (self.counters[word] >> shift) & 0xF < u64::from(MAX_COUNT) {
+                    self.counters[word] += 1 << shift;
+                }
+            }
+        }
+        for b in bits {
+            self.doorkeeper[b / 64] |= 1 << (b % 64);
+        }
+        self.additions += 1;
+    }
+
+    /// Estimate how many times the key with the given hash has been requested recently. This can
+    /// overestimate, but never underestimates, up to a maximum of [`MAX_COUNT`] + 1.
+    pub(crate) fn estimate(&self, hash: u64) -> u32 {
+        let door = self.seen(self.door_bits(hash));
+        let count = self
+            .counter_slots(hash)
+            .iter()
+            .map(|&(word, shift)| ((self.counters[word] >> shift) & 0xF) as u32)
+            .min()
+            .unwrap_or(0);
+        count + u32::from(door)
+    }
+
+    /// Start a new period: halve every count, and start a new doorkeeper.
+    fn age(&mut self) {
+        for word in self.counters.iter_mut() {
+            *word = (*word >> 1) & 0x7777_7777_7777_7777;
+        }
+        std::mem::swap(&mut self.doorkeeper, &mut self.previous_doorkeeper);
+        self.doorkeeper.fill(0);
+        self.additions = 0;
+    }
+
+    /// Forget all requests.
+    pub(crate) fn clear(&mut self) {
+        self.clear_counts();
+        self.positions.clear();
+    }
+
+    /// Reset every count, without changing the positions of remembered requests.
+    pub(crate) fn clear_counts(&mut self) {
+        self.counters.fill(0);
+        self.doorkeeper.fill(0);
+        self.previous_doorkeeper.fill(0);
+        self.additions = 0;
+    }
+}
@@ -0,0 +1,392 @@
+//! Serializable snapshots of a cache's state.
+
+use crate::{
+    DynamicCache, DynamicCacheLocal, EvictionCounts, Evictions, MemTuner, Record, SketchHistory,
+    SystemClock,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::hash::{BuildHasher, Hash};
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// The snapshot format version written by this version of the crate. Snapshots with any other
+/// version are rejected.
+const SNAPSHOT_VERSION: u32 = 1;
+
+/// Hashed with a cache's hash builder to check whether a restored sketch's counts still apply.
+const HASH_PROBE: u64 = 0x6361_6368_6520_6b65;
+
+/// A serializable copy of a [`DynamicCacheLocal`]'s or [`DynamicCache`]'s state, made with
+/// [`snapshot`](DynamicCacheLocal::snapshot) and turned back into a cache with
+/// [`from_snapshot`](DynamicCacheLocal::from_snapshot).
+///
+/// This holds the recent request memory along with each key's request count, every stored value,
+/// the settings that decide what gets stored, and the cache's metrics, so a restored cache makes
+/// the same admission decisions the original would have. Request times and time-to-lives are kept
+/// relative to when the snapshot was made.
+///
+/// The weigher, maximum weight, clock, eviction listener and [weak
+/// values](DynamicCacheLocal::set_weak_values) setting aren't part of a snapshot, and need to be
+/// set again on the restored cache. Ghost keys kept for [automatic memory
+/// tuning](DynamicCacheLocal::set_auto_mem_len) aren't kept either, though the bounds are.
+#[derive(Serialize, Deserialize)]
+pub struct CacheSnapshot<K, V> {
+    version: u32,
+    mem_len: usize,
+    mem_duration: Option<Duration>,
+    admit_threshold: u32,
+    max_entries: usize,
+    ttl: Option<Duration>,
+    auto_mem_len: Option<RangeInclusive<usize>>,
+    /// Every tracked key, with its request counter, its stored value if any, and how long until
+    /// that value expires.
+    entries: Vec<SnapshotEntry<K, V>>,
+    /// The recent request memory, newest first. Each request holds the index of its key in
+    /// `entries`, the key's request counter at the time, and how long ago it was made if the
+    /// memory is time-limited.
+    history: Vec<(usize, u32, Option<Duration>)>,
+    sketch: Option<SketchHistory>,
+    /// [`HASH_PROBE`] hashed by the original cache, if it had a sketch.
+    hash_probe: Option<u64>,
+    hits: u64,
+    misses: u64,
+    admissions: u64,
+    rejections: u64,
+    pops: u64,
+    resurrections: u64,
+    evictions: EvictionCounts,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SnapshotEntry<K, V> {
+    key: K,
+    counter: u32,
+    value: Option<Arc<V>>,
+    expires_in: Option<Duration>,
+    pinned: bool,
+}
+
+impl<K: Clone, V> Clone for SnapshotEntry<K, V> {
+    fn clone(&self) -> Self {
+        Self {
+            key: self.key.clone(),
+            value: self.value.clone(),
+            ..*self
+        }
+    }
+}
+
+impl<K, V> CacheSnapshot<K, V> {
+    /// Get the format version of the snapshot.
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// Get the memory length of the cache the snapshot was made from.
+    pub fn mem_len(&self) -> usize {
+        self.mem_len
+    }
+
+    /// Get the number of values stored in the snapshot.
+    pub fn size(&self) -> usize {
+        self.entries.iter().filter(|e| e.value.is_some()).count()
+    }
+}
+
+impl<K: Clone, V> Clone for CacheSnapshot<K, V> {
+    fn clone(&self) -> Self {
+        Self {
+            entries: self.entries.clone(),
+            history: self.history.clone(),
+            sketch: self.sketch.clone(),
+            auto_mem_len: self.auto_mem_len.clone(),
+            ..*self
+        }
+    }
+}
+
+impl<K, V> fmt::Debug for CacheSnapshot<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CacheSnapshot")
+            .field("version", &self.version)
+            .field("mem_len", &self.mem_len)
+            .field("entries", &format!("{} entries", self.entries.len()))
+            .field("history", &format!("{} long", self.history.len()))
+            .field("sketch", &self.sketch.is_some())
+            .finish()
+    }
+}
+
+/// The reason a [`CacheSnapshot`] couldn't be restored.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SnapshotError {
+    /// The snapshot was made by an incompatible version of this crate. Holds the snapshot's
+    /// format version.
+    Version(u32),
+    /// The snapshot's contents don't fit together, so it wasn't made by this crate or was changed
+    /// afterwards. Holds a description of the problem.
+    Invalid(&'static str),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::Version(version) => write!(
+                f,
+                "cache snapshot has format version {version}, expected {SNAPSHOT_VERSION}"
+            ),
+            SnapshotError::Invalid(reason) => write!(f, "invalid cache snapshot: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl<K: Clone + Eq + Hash, V> DynamicCacheLocal<K, V> {
+    /// Create a cache from a snapshot of another cache's state. See
+    /// [`from_snapshot_with_hasher`](Self::from_snapshot_with_hasher).
+    pub fn from_snapshot(snapshot: CacheSnapshot<K, V>) -> Result<Self, SnapshotError> {
+        Self::from_snapshot_with_hasher(snapshot, RandomState::new())
+    }
+}
+
+impl<K: Clone + Eq + Hash, V, S: BuildHasher> DynamicCacheLocal<K, V, S> {
+    /// Take a snapshot of the cache's state, which can be serialized and later restored with
+    /// [`from_snapshot`](Self::from_snapshot). Expired values are left out.
+    pub fn snapshot(&self) -> CacheSnapshot<K, V> {
+        let now = self.clock.now();
+        let mut index = HashMap::with_capacity(self.map.len());
+        let entries = self
+            .map
+            .iter()
+            .enumerate()
+            .map(|(i, (key, entry))| {
+                index.insert(key, i);
+                let value = entry.value.clone().filter(|_| !entry.expired(&*self.clock));
+                SnapshotEntry {
+                    key: key.clone(),
+                    counter: entry.counter,
+                    expires_in: entry
+                        .expires
+                        .filter(|_| value.is_some())
+                        .map(|at| at.saturating_duration_since(now)),
+                    value,
+                    pinned: entry.pinned,
+                }
+            })
+            .collect();
+        let history = self
+            .list
+            .iter()
+            .map(|(key, counter, at)| {
+                let age = at.map(|at| now.saturating_duration_since(at));
+                (index[key], *counter, age)
+            })
+            .collect();
+
+        CacheSnapshot {
+            version: SNAPSHOT_VERSION,
+            mem_len: self.mem_len,
+            mem_duration: self.mem_duration,
+            admit_threshold: self.admit_threshold,
+            max_entries: self.max_entries,
+            ttl: self.ttl,
+            auto_mem_len: self.auto_mem_len(),
+            entries,
+            history,
+            sketch: self.sketch.as_deref().cloned(),
+            hash_probe: self
+                .sketch
+                .as_ref()
+                .map(|_| self.map.hasher().hash_one(HASH_PROBE)),
+            hits: self.hits,
+            misses: self.misses,
+            admissions: self.admissions,
+            rejections: self.rejections,
+            pops: self.pops,
+            resurrections: self.resurrections,
+            evictions: self.evictions.counts,
+        }
+    }
+
+    /// Create a cache from a snapshot of another cache's state, using the given hash builder to
+    /// hash keys. The restored cache remembers the same requests and stores the same values as
+    /// the original did when the snapshot was made.
+    ///
+    /// A [sketch](Self::with_sketch_and_hasher)'s counts are looked up by key hash, so they're
+    /// only kept if the hash builder hashes keys the same way as the original cache's did, which
+    /// [`RandomState`] never does. Otherwise they start over, though keys that were being tracked
+    /// individually are still remembered.
+    ///
+    /// Fails if the snapshot was made with an incompatible version of this crate, or if its
+    /// contents don't fit together.
+    pub fn from_snapshot_with_hasher(
+        snapshot: CacheSnapshot<K, V>,
+        hash_builder: S,
+    ) -> Result<Self, SnapshotError> {
+        if snapshot.version != SNAPSHOT_VERSION {
+            return Err(SnapshotError::Version(snapshot.version));
+        }
+        let mem_len = snapshot.mem_len.clamp(2, u32::MAX as usize);
+        if snapshot.history.len() > mem_len {
+            return Err(SnapshotError::Invalid("history is longer than the memory"));
+        }
+        if snapshot
+            .auto_mem_len
+            .as_ref()
+            .is_some_and(|bounds| bounds.start() > bounds.end())
+        {
+            return Err(SnapshotError::Invalid("memory length bounds are empty"));
+        }
+        if let Some(sketch) = &snapshot.sketch {
+            if !sketch.is_valid() || sketch.positions.len() != snapshot.history.len() {
+                return Err(SnapshotError::Invalid("sketch doesn't match the history"));
+            }
+        }
+
+        // Each key's requests still in memory, the counter of its newest request, and how far
+        // behind that the counter of its oldest request so far is
+        let mut requests = vec![(0u32, None, 0u32); snapshot.entries.len()];
+        for &(i, counter, _) in &snapshot.history {
+            let (recent, newest, behind) = requests
+                .get_mut(i)
+                .ok_or(SnapshotError::Invalid("history refers to a missing key"))?;
+            match newest {
+                None => *newest = Some(counter),
+                Some(newest) => {
+                    // Counters wrap around, but a key never has more requests in memory than fit
+                    // in a counter, so each older request must be further behind the newest one
+                    let distance = newest.wrapping_sub(counter);
+                    if distance <= *behind {
+                        return Err(SnapshotError::Invalid(
+                            "key counters in the history are out of order",
+                        ));
+                    }
+                    *behind = distance;
+                }
+            }
+            *recent += 1;
+        }
+
+        let now = Instant::now();
+        let mut map = HashMap::with_capacity_and_hasher(snapshot.entries.len(), hash_builder);
+        let mut keys = Vec::with_capacity(snapshot.entries.len());
+        let mut size = 0;
+        let mut pinned = 0;
+        let mut idle_pins = 0;
+        for (entry, (recent, newest, _)) in snapshot.entries.into_iter().zip(requests) {
+            let pin = entry.pinned && entry.value.is_some();
+            // Pinned values are kept without any requests in memory
+            let idle = pin && newest.is_none();
+            if newest != Some(entry.counter) && !idle {
+                return Err(SnapshotError::Invalid(
+                    "key counter doesn't match the history",
+                ));
+            }
+            let expires = match entry.expires_in.filter(|_| !pin) {
+                Some(d) => Some(
+                    now.checked_add(d)
+                        .ok_or(SnapshotError::Invalid("value expiry is out of range"))?,
+                ),
+                None => None,
+            };
+            size += usize::from(entry.value.is_some());
+            pinned += usize::from(pin);
+            idle_pins += usize::from(idle);
+            let record = Record {
+                counter: entry.counter,
+                recent,
+                value: entry.value,
+                weight: 1,
+                expires,
+                pinned: pin,
+            };
+            if map.insert(entry.key.clone(), record).is_some() {
+                return Err(SnapshotError::Invalid("key appears more than once"));
+            }
+            keys.push(entry.key);
+        }
+        // Pinned values may go over the limit
+        if size - pinned > snapshot.max_entries {
+            return Err(SnapshotError::Invalid(
+                "more values than the maximum entry count",
+            ));
+        }
+
+        let list: VecDeque<_> = snapshot
+            .history
+            .into_iter()
+            .map(|(i, counter, age)| {
+                let at = age.map(|age| now.checked_sub(age).unwrap_or(now));
+                (keys[i].clone(), counter, at)
+            })
+            .collect();
+
+        let same_hashes = snapshot.hash_probe == Some(map.hasher().hash_one(HASH_PROBE));
+        let mut evictions = Evictions::new();
+        evictions.counts = snapshot.evictions;
+        Ok(Self {
+            map,
+            list,
+            mem_len,
+            mem_duration: snapshot.mem_duration,
+            admit_threshold: snapshot.admit_threshold.max(1),
+            weigher: None,
+            max_weight: usize::MAX,
+            weight: size,
+            max_entries: snapshot.max_entries,
+            ttl: snapshot.ttl,
+            clock: Box::new(SystemClock),
+            evictions,
+            sketch: snapshot.sketch.map(|mut sketch| {
+                if !same_hashes {
+                    sketch.clear_counts();
+                }
+                Box::new(sketch)
+            }),
+            weak: None,
+            idle_pins,
+            tuner: snapshot.auto_mem_len.map(|bounds| {
+                let min = (*bounds.start()).clamp(2, u32::MAX as usize);
+                let max = (*bounds.end()).clamp(min, u32::MAX as usize);
+                Box::new(MemTuner::new(min..=max))
+            }),
+            size,
+            hits: snapshot.hits,
+            misses: snapshot.misses,
+            admissions: snapshot.admissions,
+            rejections: snapshot.rejections,
+            pops: snapshot.pops,
+            resurrections: snapshot.resurrections,
+        })
+    }
+}
+
+impl<K: Clone + Eq + Hash, V> DynamicCache<K, V> {
+    /// Create a cache from a snapshot of another cache's state. See
+    /// [`DynamicCacheLocal::from_snapshot_with_hasher`].
+    pub fn from_snapshot(snapshot: CacheSnapshot<K, V>) -> Result<Self, SnapshotError> {
+        Self::from_snapshot_with_hasher(snapshot, RandomState::new())
+    }
+}
+
+impl<K: Clone + Eq + Hash, V, S: BuildHasher> DynamicCache<K, V, S> {
+    /// Take a snapshot of the cache's state. See [`DynamicCacheLocal::snapshot`].
+    pub fn snapshot(&self) -> CacheSnapshot<K, V> {
+        self.cache.lock().unwrap().snapshot()
+    }
+
+    /// Create a cache from a snapshot of another cache's state, using the given hash builder to
+    /// hash keys. See [`DynamicCacheLocal::from_snapshot_with_hasher`].
+    pub fn from_snapshot_with_hasher(
+        snapshot: CacheSnapshot<K, V>,
+        hash_builder: S,
+    ) -> Result<Self, SnapshotError> {
+        DynamicCacheLocal::from_snapshot_with_hasher(snapshot, hash_builder).map(Self::from_local)
+    }
+}
@@ -0,0 +1,186 @@
+//! Snapshots of cache statistics.
+
+use crate::EvictionReason;
+use std::ops::{Add, Sub};
+
+/// A snapshot of a cache's statistics, as returned by [`DynamicCacheLocal::stats`] and the
+/// `stats` method on the other cache types.
+///
+/// The counters cover everything since the cache was created or its metrics were last reset. The
+/// remaining fields describe the cache at the time of the snapshot.
+///
+/// Subtracting an earlier snapshot from a later one gives the counts for the time in between,
+/// along with the later snapshot's current state. Adding snapshots totals everything, which is
+/// useful for combining several caches.
+///
+/// [`DynamicCacheLocal::stats`]: crate::DynamicCacheLocal::stats
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Requests that found a stored value.
+    pub hits: u64,
+    /// Requests that didn't find a stored value.
+    pub misses: u64,
+    /// Inserts that stored their value.
+    pub admissions: u64,
+    /// Inserts that didn't store their value, because the key hadn't been requested enough times
+    /// or the value was too heavy. Inserts returning an already stored value aren't counted.
+    pub rejections: u64,
+    /// Calls to `pop`, whether or not they found a value. The values they removed are also
+    /// counted in [`EvictionCounts::removed`].
+    pub pops: u64,
+    /// Requests that found a value which had already left the cache, but was still alive
+    /// elsewhere, with [weak values](crate::DynamicCacheLocal::set_weak_values) on. These are also
+    /// counted as hits.
+    pub resurrections: u64,
+    /// Values that left the cache, by reason.
+    pub evictions: EvictionCounts,
+    /// Number of requests currently in the cache's recent request memory.
+    pub history_len: usize,
+    /// Number of distinct keys currently in the cache's recent request memory.
+    pub tracked: usize,
+    /// Number of values currently stored.
+    pub stored: usize,
+}
+
+impl CacheStats {
+    /// Get the fraction of requests that were hits, or 0 if there haven't been any requests.
+    pub fn hit_ratio(&self) -> f64 {
+        let requests = self.hits + self.misses;
+        if requests == 0 {
+            0.0
+        } else {
+            self.hits as f64 / requests as f64
+        }
+    }
+}
+
+impl Sub for CacheStats {
+    type Output = CacheStats;
+
+    /// Get the counts between two snapshots, keeping the current state from `self`. Counters
+    /// that went down, such as after a reset, saturate at 0.
+    fn sub(self, rhs: CacheStats) -> CacheStats {
+        CacheStats {
+            hits: self.hits.saturating_sub(rhs.hits),
+            misses: self.misses.saturating_sub(rhs.misses),
+            admissions: self.admissions.saturating_sub(rhs.admissions),
+            rejections: self.rejections.saturating_sub(rhs.rejections),
+            pops: self.pops.saturating_sub(rhs.pops),
+            resurrections: self.resurrections.saturating_sub(rhs.resurrections),
+            evictions: self.evictions - rhs.evictions,
+            ..self
+        }
+    }
+}
+
+impl Add for CacheStats {
+    type Output = CacheStats;
+
+    fn add(self, rhs: CacheStats) -> CacheStats {
+        CacheStats {
+            hits: self.hits + rhs.hits,
+            misses: self.misses + rhs.misses,
+            admissions: self.admissions + rhs.admissions,
+            rejections: self.rejections + rhs.rejections,
+            pops: self.pops + rhs.pops,
+            resurrections: self.resurrections + rhs.resurrections,
+            evictions: self.evictions + rhs.evictions,
+            history_len: self.history_len + rhs.history_len,
+            tracked: self.tracked + rhs.tracked,
+            stored: self.stored + rhs.stored,
+        }
+    }
+}
+
+/// The number of values that left a cache for each [`EvictionReason`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct EvictionCounts {
+    /// See [`EvictionReason::Forgotten`].
+    pub forgotten: u64,
+    /// See [`EvictionReason::Expired`].
+    pub expired: u64,
+    /// See [`EvictionReason::Removed`].
+    pub removed: u64,
+    /// See [`EvictionReason::Replaced`].
+    pub replaced: u64,
+    /// See [`EvictionReason::Resized`].
+    pub resized: u64,
+    /// See [`EvictionReason::Cleared`].
+    pub cleared: u64,
+    /// See [`EvictionReason::Capacity`].
+    pub capacity: u64,
+}
+
+impl EvictionCounts {
+    /// Get the count for a single reason.
+    pub fn get(&self, reason: EvictionReason) -> u64 {
+        match reason {
+            EvictionReason::Forgotten => self.forgotten,
+            EvictionReason::Expired => self.expired,
+            EvictionReason::Removed => self.removed,
+            EvictionReason::Replaced => self.replaced,
+            EvictionReason::Resized => self.resized,
+            EvictionReason::Cleared => self.cleared,
+            EvictionReason::Capacity => self.capacity,
+        }
+    }
+
+    /// Get the total count for all reasons.
+    pub fn total(&self) -> u64 {
+        self.forgotten
+            + self.expired
+            + self.removed
+            + self.replaced
+            + self.resized
+            + self.cleared
+            + self.capacity
+    }
+
+    pub(crate) fn record(&mut self, reason: EvictionReason) {
+        let count = match reason {
+            EvictionReason::Forgotten => &mut self.forgotten,
+            EvictionReason::Expired => &mut self.expired,
+            EvictionReason::Removed => &mut self.removed,
+            EvictionReason::Replaced => &mut self.replaced,
+            EvictionReason::Resized => &mut self.resized,
+            EvictionReason::Cleared => &mut self.cleared,
+            EvictionReason::Capacity => &mut self.capacity,
+        };
+        *count += 1;
+    }
+}
+
+impl Sub for EvictionCounts {
+    type Output = EvictionCounts;
+
+    /// Get the counts between two snapshots. Counts that went down, such as after a reset,
+    /// saturate at 0.
+    fn sub(self, rhs: EvictionCounts) -> EvictionCounts {
+        EvictionCounts {
+            forgotten: self.forgotten.saturating_sub(rhs.forgotten),
+            expired: self.expired.saturating_sub(rhs.expired),
+            removed: self.removed.saturating_sub(rhs.removed),
+            replaced: self.replaced.saturating_sub(rhs.replaced),
+            resized: self.resized.saturating_sub(rhs.resized),
+            cleared: self.cleared.saturating_sub(rhs.cleared),
+            capacity: self.capacity.saturating_sub(rhs.capacity),
+        }
+    }
+}
+
+impl Add for EvictionCounts {
+    type Output = EvictionCounts;
+
+    fn add(self, rhs: EvictionCounts) -> EvictionCounts {
+        EvictionCounts {
+            forgotten: self.forgotten + rhs.forgotten,
+            expired: self.expired + rhs.expired,
+            removed: self.removed + rhs.removed,
+            replaced: self.replaced + rhs.replaced,
+            resized: self.resized + rhs.resized,
+            cleared: self.cleared + rhs.cleared,
+            capacity: self.capacity + rhs.capacity,
+        }
+    }
+}
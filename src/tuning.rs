@@ -0,0 +1,133 @@
+//! Automatic tuning of a cache's memory length.
+
+use crate::fingerprint::FingerprintHasher;
+use std::collections::{HashMap, VecDeque};
+use std::hash::BuildHasherDefault;
+use std::ops::RangeInclusive;
+
+/// A share of ghost hits above this means a longer memory would have stored more values.
+const GROW_GHOST_RATIO: f64 = 1.0 / 64.0;
+/// A share of ghost hits below this means the memory is longer than it needs to be.
+const SHRINK_GHOST_RATIO: f64 = 1.0 / 256.0;
+/// How far the hit ratio may drop after shrinking the memory before the shrink is undone.
+const HIT_RATIO_TOLERANCE: f64 = 1.0 / 64.0;
+
+/// Get the memory length one step up from `mem_len`.
+fn grown(mem_len: usize) -> usize {
+    mem_len + mem_len / 4 + 1
+}
+
+/// A change made to the memory length at the end of a period.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Step {
+    Grow,
+    Shrink,
+    Hold,
+}
+
+/// Watches a cache's requests and decides how long its memory should be.
+///
+/// Requests are looked at in periods of one memory length each. A "ghost hit" is a request for a
+/// key the cache forgot recently: with a longer memory the key would still have been tracked, and
+/// maybe stored. Plenty of ghost hits grow the memory, while hardly any shrink it, unless the last
+/// shrink cost more hits than it was worth.
+pub(crate) struct MemTuner {
+    pub(crate) bounds: RangeInclusive<usize>,
+    /// Hashes of recently forgotten keys, newest first, along with how many times each appears.
+    ghosts: VecDeque<u64>,
+    ghost_counts: HashMap<u64, u32, BuildHasherDefault<FingerprintHasher>>,
+    /// The memory isn't shrunk below this, after a shrink to below it had to be undone.
+    floor: usize,
+    requests: usize,
+    hits: usize,
+    ghost_hits: usize,
+    /// The last step taken, and the hit ratio in the period before it.
+    last: (Step, f64),
+}
+
+impl MemTuner {
+    pub(crate) fn new(bounds: RangeInclusive<usize>) -> Self {
+        Self {
+            floor: *bounds.start(),
+            bounds,
+            ghosts: VecDeque::new(),
+            ghost_counts: HashMap::default(),
+            requests: 0,
+            hits: 0,
+            ghost_hits: 0,
+            last: (Step::Hold, 0.0),
+        }
+    }
+
+    /// Remember that the key with the given hash was forgotten. Only as many keys as a memory at
+    /// the upper bound would still be tracking are remembered this way.
+    pub(crate) fn forgot(&mut self, hash: u64, mem_len: usize) {
+        self.ghosts.push_front(hash);
+        *self.ghost_counts.entry(hash).or_default() += 1;
+        while self.ghosts.len() > self.bounds.end().saturating_sub(mem_len) {
+            let old = self.ghosts.pop_back().unwrap();
+            let count = self.ghost_counts.get_mut(&old).unwrap();
+            *count -= 1;
+            if *count == 0 {
+                self.ghost_counts.remove(&old);
+            }
+        }
+    }
+
+    /// Note a request for an untracked key, counting a ghost hit if it was forgotten recently.
+    pub(crate) fn untracked(&mut self, hash: u64) {
+        if self.ghost_counts.contains_key(&hash) {
+            self.ghost_hits += 1;
+        }
+    }
+
+    /// Count a request. At the end of each period, this returns the new memory length to use if
+    /// it should change. `full` is whether the cache is already storing as much as it can.
+    pub(crate) fn request(&mut self, hit: bool, mem_len: usize, full: bool) -> Option<usize> {
+        self.requests += 1;
+        self.hits += usize::from(hit);
+        if self.requests < mem_len {
+            return None;
+        }
+
+        let hit_ratio = self.hits as f64 / self.requests as f64;
+        let ghost_ratio = self.ghost_hits as f64 / self.requests as f64;
+        let (last_step, last_hit_ratio) = self.last;
+        let shrink_hurt = last_step == Step::Shrink
+            && (ghost_ratio > GROW_GHOST_RATIO || hit_ratio + HIT_RATIO_TOLERANCE < last_hit_ratio);
+        let step = if shrink_hurt {
+            // Undo the shrink, and don't try it again
+            self.floor = grown(mem_len).min(*self.bounds.end());
+            Step::Grow
+        } else if ghost_ratio > GROW_GHOST_RATIO && !full {
+            Step::Grow
+        } else if ghost_ratio < SHRINK_GHOST_RATIO && mem_len - mem_len / 8 >= self.floor {
+            Step::Shrink
+        } else {
+            Step::Hold
+        };
+        self.last = (step, hit_ratio);
+        self.requests = 0;
+        self.hits = 0;
+        self.ghost_hits = 0;
+
+        let new_len = match step {
+            Step::Grow => grown(mem_len),
+            Step::Shrink => mem_len - mem_len / 8,
+            Step::Hold => mem_len,
+        };
+        let new_len = new_len.clamp(*self.bounds.start(), *self.bounds.end());
+        (new_len != mem_len).then_some(new_len)
+    }
+
+    /// Forget all requests and ghosts, and allow the memory to shrink again.
+    pub(crate) fn clear(&mut self) {
+        self.ghosts.clear();
+        self.ghost_counts.clear();
+        self.requests = 0;
+        self.hits = 0;
+        self.ghost_hits = 0;
+        self.floor = *self.bounds.start();
+        self.last = (Step::Hold, 0.0);
+    }
+}
@@ -0,0 +1,72 @@
+//! Weak references to values that have left the cache but may still be in use.
+
+use crate::SharedPtr;
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash};
+
+/// The smallest length at which dead references are pruned.
+const MIN_PRUNE_LEN: usize = 64;
+
+/// Values evicted from the cache while something else still held them, kept as weak references
+/// so they can be handed out again for as long as they stay alive.
+pub(crate) struct WeakValues<K, V, P: SharedPtr<V>, S> {
+    map: HashMap<K, P::Weak, S>,
+    /// Dead references are pruned once the map reaches this length, so it's never more than about
+    /// twice as long as it needs to be.
+    prune_len: usize,
+}
+
+impl<K: Eq + Hash, V, P: SharedPtr<V>, S: BuildHasher> WeakValues<K, V, P, S> {
+    /// Create an empty set of weak references, hashing keys with the cache's hash builder.
+    pub(crate) fn with_hasher(hash_builder: S) -> Self {
+        Self {
+            map: HashMap::with_hasher(hash_builder),
+            prune_len: MIN_PRUNE_LEN,
+        }
+    }
+
+    /// Keep a weak reference to an evicted value, unless the cache held the only reference to it.
+    pub(crate) fn insert(&mut self, key: K, value: &P) {
+        if P::strong_count(value) == 1 {
+            return;
+        }
+        if self.map.len() >= self.prune_len {
+            self.map.retain(|_, v| P::is_alive(v));
+            self.prune_len = (self.map.len() * 2).max(MIN_PRUNE_LEN);
+        }
+        self.map.insert(key, P::downgrade(value));
+    }
+
+    /// Check whether a value for the key is still alive.
+    pub(crate) fn contains<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.map.get(key).is_some_and(P::is_alive)
+    }
+
+    /// Take the value for the key, if it's still alive.
+    pub(crate) fn take<Q>(&mut self, key: &Q) -> Option<P>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        P::upgrade(&self.map.remove(key)?)
+    }
+
+    /// Forget the value for the key.
+    pub(crate) fn remove<Q>(&mut self, key: &Q)
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.map.remove(key);
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.map.clear();
+        self.prune_len = MIN_PRUNE_LEN;
+    }
+}